@@ -0,0 +1,522 @@
+use crate::{CellInfo, FoodInfo, MAP_HEIGHT, MAP_WIDTH};
+use deku::bitvec::{BitSlice, BitVec, Msb0};
+use deku::ctx::BitSize;
+use deku::prelude::*;
+use std::collections::BTreeMap;
+
+// Positions, radii and velocities are sent as fixed-point numbers, each
+// covering its whole range with this many bits. The error is at most
+// half a step, eg: `MAP_WIDTH / 2^POSITION_BITS / 2` for positions.
+pub const POSITION_BITS: usize = 16;
+pub const VELOCITY_BITS: usize = 12;
+
+// Velocities are clamped to +/- this many 'pixels/second'.
+pub const MAX_VELOCITY: f32 = 1000.0;
+
+// Positions are relative to the map's center and clamped to it.
+pub(crate) const X_RANGE: (f32, f32) = (-MAP_WIDTH / 2.0, MAP_WIDTH / 2.0);
+pub(crate) const Y_RANGE: (f32, f32) = (-MAP_HEIGHT / 2.0, MAP_HEIGHT / 2.0);
+// No cell grows wider than the map.
+pub(crate) const RADIUS_RANGE: (f32, f32) = (0.0, MAP_WIDTH / 2.0);
+const VELOCITY_RANGE: (f32, f32) = (-MAX_VELOCITY, MAX_VELOCITY);
+
+// The largest value sent with `bits` bits. Ranges are split in an even
+// number of steps, so that their middle (eg: a velocity of zero or the
+// center of the map) is exact.
+fn max_step(bits: usize) -> f64 {
+  ((1u64 << bits) - 2) as f64
+}
+
+pub fn quantize(value: f32, (min, max): (f32, f32), bits: usize) -> u32 {
+  let t = ((value as f64 - min as f64) / (max as f64 - min as f64)).clamp(0.0, 1.0);
+  (t * max_step(bits)).round() as u32
+}
+
+pub fn dequantize(value: u32, (min, max): (f32, f32), bits: usize) -> f32 {
+  (min as f64 + value as f64 / max_step(bits) * (max as f64 - min as f64)) as f32
+}
+
+pub(crate) fn read_fixed(
+  rest: &BitSlice<u8, Msb0>,
+  range: (f32, f32),
+  bits: usize,
+) -> Result<(&BitSlice<u8, Msb0>, f32), DekuError> {
+  let (rest, value) = u32::read(rest, BitSize(bits))?;
+  Ok((rest, dequantize(value, range, bits)))
+}
+
+pub(crate) fn write_fixed(
+  output: &mut BitVec<u8, Msb0>,
+  value: f32,
+  range: (f32, f32),
+  bits: usize,
+) -> Result<(), DekuError> {
+  quantize(value, range, bits).write(output, BitSize(bits))
+}
+
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone, Copy)]
+pub struct CellState {
+  pub uid: u32,
+  #[deku(
+    reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *x, X_RANGE, POSITION_BITS)"
+  )]
+  pub x: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *y, Y_RANGE, POSITION_BITS)"
+  )]
+  pub y: f32,
+  // Velocity in 'pixels/second'.
+  #[deku(
+    reader = "read_fixed(deku::rest, VELOCITY_RANGE, VELOCITY_BITS)",
+    writer = "write_fixed(deku::output, *vx, VELOCITY_RANGE, VELOCITY_BITS)"
+  )]
+  pub vx: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, VELOCITY_RANGE, VELOCITY_BITS)",
+    writer = "write_fixed(deku::output, *vy, VELOCITY_RANGE, VELOCITY_BITS)"
+  )]
+  pub vy: f32,
+  pub radius: f32,
+}
+
+impl From<&CellInfo> for CellState {
+  fn from(info: &CellInfo) -> Self {
+    CellState {
+      uid: info.uid,
+      x: info.x,
+      y: info.y,
+      vx: 0.0,
+      vy: 0.0,
+      radius: info.radius,
+    }
+  }
+}
+
+// A pellet of ejected mass, it moves until it slows down to a stop.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone, Copy)]
+pub struct PelletState {
+  pub id: u32,
+  #[deku(
+    reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *x, X_RANGE, POSITION_BITS)"
+  )]
+  pub x: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *y, Y_RANGE, POSITION_BITS)"
+  )]
+  pub y: f32,
+  // Velocity in 'pixels/second'.
+  #[deku(
+    reader = "read_fixed(deku::rest, VELOCITY_RANGE, VELOCITY_BITS)",
+    writer = "write_fixed(deku::output, *vx, VELOCITY_RANGE, VELOCITY_BITS)"
+  )]
+  pub vx: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, VELOCITY_RANGE, VELOCITY_BITS)",
+    writer = "write_fixed(deku::output, *vy, VELOCITY_RANGE, VELOCITY_BITS)"
+  )]
+  pub vy: f32,
+}
+
+// A virus, it only moves after being shot by another one.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone, Copy)]
+pub struct VirusState {
+  pub id: u32,
+  #[deku(
+    reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *x, X_RANGE, POSITION_BITS)"
+  )]
+  pub x: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *y, Y_RANGE, POSITION_BITS)"
+  )]
+  pub y: f32,
+  pub radius: f32,
+}
+
+// The state of the world as sent to one client.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Snapshot {
+  pub seq: u32,
+  // Last input of the client that was applied when it was taken.
+  pub input: u32,
+  pub cells: BTreeMap<u32, CellState>,
+  // id -> (x, y)
+  pub food: BTreeMap<u32, (f32, f32)>,
+  pub pellets: BTreeMap<u32, PelletState>,
+  pub viruses: BTreeMap<u32, VirusState>,
+}
+
+// What changed between two snapshots. Only the cells and food that
+// differ from the `base` snapshot are sent, use `Snapshot::diff` to
+// build one.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+pub struct Delta {
+  pub seq: u32,
+  pub base: u32,
+  pub input: u32,
+  cell_count: u16,
+  // New cells and cells that moved or grew.
+  #[deku(count = "cell_count")]
+  pub cells: Vec<CellState>,
+  removed_cell_count: u16,
+  #[deku(count = "removed_cell_count")]
+  pub removed_cells: Vec<u32>,
+  food_count: u16,
+  #[deku(count = "food_count")]
+  pub food: Vec<FoodInfo>,
+  removed_food_count: u16,
+  #[deku(count = "removed_food_count")]
+  pub removed_food: Vec<u32>,
+  pellet_count: u16,
+  // New pellets and pellets that moved.
+  #[deku(count = "pellet_count")]
+  pub pellets: Vec<PelletState>,
+  removed_pellet_count: u16,
+  #[deku(count = "removed_pellet_count")]
+  pub removed_pellets: Vec<u32>,
+  virus_count: u16,
+  // New viruses and viruses that moved or grew.
+  #[deku(count = "virus_count")]
+  pub viruses: Vec<VirusState>,
+  removed_virus_count: u16,
+  #[deku(count = "removed_virus_count")]
+  pub removed_viruses: Vec<u32>,
+}
+
+fn count<T>(items: &[T]) -> u16 {
+  items.len().try_into().expect("too many changes for a delta")
+}
+
+impl Snapshot {
+  // What a client knows after applying a `WorldSnapshot`. Pellets and
+  // viruses are not part of it, they come with the first delta.
+  pub fn from_world(food: &[FoodInfo], cells: &[CellInfo]) -> Self {
+    Snapshot {
+      seq: 0,
+      input: 0,
+      cells: cells.iter().map(|cell| (cell.uid, cell.into())).collect(),
+      food: food.iter().map(|food| (food.id, (food.x, food.y))).collect(),
+      pellets: BTreeMap::new(),
+      viruses: BTreeMap::new(),
+    }
+  }
+
+  // Encode `current` relative to this snapshot.
+  pub fn diff(&self, current: &Snapshot) -> Delta {
+    let cells = current
+      .cells
+      .values()
+      .filter(|cell| self.cells.get(&cell.uid) != Some(cell))
+      .copied()
+      .collect::<Vec<_>>();
+    let removed_cells = self
+      .cells
+      .keys()
+      .filter(|uid| !current.cells.contains_key(uid))
+      .copied()
+      .collect::<Vec<_>>();
+    // Food never moves, it only appears and disappears.
+    let food = current
+      .food
+      .iter()
+      .filter(|(id, _)| !self.food.contains_key(id))
+      .map(|(id, (x, y))| FoodInfo { id: *id, x: *x, y: *y })
+      .collect::<Vec<_>>();
+    let removed_food = self
+      .food
+      .keys()
+      .filter(|id| !current.food.contains_key(id))
+      .copied()
+      .collect::<Vec<_>>();
+    let pellets = current
+      .pellets
+      .values()
+      .filter(|pellet| self.pellets.get(&pellet.id) != Some(pellet))
+      .copied()
+      .collect::<Vec<_>>();
+    let removed_pellets = self
+      .pellets
+      .keys()
+      .filter(|id| !current.pellets.contains_key(id))
+      .copied()
+      .collect::<Vec<_>>();
+    let viruses = current
+      .viruses
+      .values()
+      .filter(|virus| self.viruses.get(&virus.id) != Some(virus))
+      .copied()
+      .collect::<Vec<_>>();
+    let removed_viruses = self
+      .viruses
+      .keys()
+      .filter(|id| !current.viruses.contains_key(id))
+      .copied()
+      .collect::<Vec<_>>();
+
+    Delta {
+      seq: current.seq,
+      base: self.seq,
+      input: current.input,
+      cell_count: count(&cells),
+      cells,
+      removed_cell_count: count(&removed_cells),
+      removed_cells,
+      food_count: count(&food),
+      food,
+      removed_food_count: count(&removed_food),
+      removed_food,
+      pellet_count: count(&pellets),
+      pellets,
+      removed_pellet_count: count(&removed_pellets),
+      removed_pellets,
+      virus_count: count(&viruses),
+      viruses,
+      removed_virus_count: count(&removed_viruses),
+      removed_viruses,
+    }
+  }
+
+  // Decode a delta that was built against this snapshot.
+  pub fn apply(&self, delta: &Delta) -> Snapshot {
+    let mut snapshot = self.clone();
+    snapshot.seq = delta.seq;
+    snapshot.input = delta.input;
+    for uid in &delta.removed_cells {
+      snapshot.cells.remove(uid);
+    }
+    for cell in &delta.cells {
+      snapshot.cells.insert(cell.uid, *cell);
+    }
+    for id in &delta.removed_food {
+      snapshot.food.remove(id);
+    }
+    for food in &delta.food {
+      snapshot.food.insert(food.id, (food.x, food.y));
+    }
+    for id in &delta.removed_pellets {
+      snapshot.pellets.remove(id);
+    }
+    for pellet in &delta.pellets {
+      snapshot.pellets.insert(pellet.id, *pellet);
+    }
+    for id in &delta.removed_viruses {
+      snapshot.viruses.remove(id);
+    }
+    for virus in &delta.viruses {
+      snapshot.viruses.insert(virus.id, *virus);
+    }
+    snapshot
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Message;
+  use std::ffi::CString;
+
+  fn cell(uid: u32, x: f32, y: f32, radius: f32) -> CellState {
+    CellState {
+      uid,
+      x,
+      y,
+      vx: 0.0,
+      vy: 0.0,
+      radius,
+    }
+  }
+
+  // Half a quantization step, plus some room for f32 rounding.
+  fn tolerance(range: (f32, f32), bits: usize) -> f32 {
+    ((range.1 - range.0) as f64 / max_step(bits) / 2.0) as f32 + 1e-3
+  }
+
+  fn snapshot(seq: u32, cells: &[CellState], food: &[(u32, f32, f32)]) -> Snapshot {
+    Snapshot {
+      seq,
+      cells: cells.iter().map(|cell| (cell.uid, *cell)).collect(),
+      food: food.iter().map(|(id, x, y)| (*id, (*x, *y))).collect(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn only_changes_are_encoded() {
+    let base = snapshot(
+      1,
+      &[cell(0, 0.0, 0.0, 50.0), cell(1, 10.0, 10.0, 50.0), cell(2, 5.0, 5.0, 50.0)],
+      &[(0, 1.0, 1.0), (1, 2.0, 2.0)],
+    );
+    let current = snapshot(
+      2,
+      &[cell(0, 0.0, 0.0, 50.0), cell(1, 20.0, 10.0, 51.0), cell(3, 0.0, 0.0, 50.0)],
+      &[(1, 2.0, 2.0), (2, 3.0, 3.0)],
+    );
+
+    let delta = base.diff(&current);
+    assert_eq!((delta.base, delta.seq), (1, 2));
+    assert_eq!(delta.cells, vec![cell(1, 20.0, 10.0, 51.0), cell(3, 0.0, 0.0, 50.0)]);
+    assert_eq!(delta.removed_cells, vec![2]);
+    assert_eq!(delta.food, vec![FoodInfo { id: 2, x: 3.0, y: 3.0 }]);
+    assert_eq!(delta.removed_food, vec![0]);
+  }
+
+  #[test]
+  fn delta_round_trip() {
+    let base = snapshot(7, &[cell(0, 0.0, 0.0, 50.0), cell(1, 10.0, 10.0, 50.0)], &[(0, 1.0, 1.0)]);
+    let mut current = snapshot(8, &[cell(1, -3.5, 12.25, 60.0), cell(4, 100.0, -100.0, 50.0)], &[(5, 2.0, 2.0)]);
+    current.input = 3;
+
+    // Through the wire and back.
+    let bytes = Message::Delta(base.diff(&current)).to_bytes().unwrap();
+    let msg = Message::try_from(bytes.as_ref()).unwrap();
+    let delta = match msg {
+      Message::Delta(delta) => delta,
+      msg => panic!("expected a delta, got {:?}", msg),
+    };
+
+    // Positions are quantized on the wire, everything else is exact.
+    let decoded = base.apply(&delta);
+    assert_eq!(decoded.seq, current.seq);
+    assert_eq!(decoded.input, current.input);
+    assert_eq!(decoded.food.keys().collect::<Vec<_>>(), current.food.keys().collect::<Vec<_>>());
+    for ((x, y), (food_x, food_y)) in decoded.food.values().zip(current.food.values()) {
+      assert!((x - food_x).abs() <= tolerance(X_RANGE, POSITION_BITS));
+      assert!((y - food_y).abs() <= tolerance(Y_RANGE, POSITION_BITS));
+    }
+    assert_eq!(decoded.cells.keys().collect::<Vec<_>>(), current.cells.keys().collect::<Vec<_>>());
+    for (decoded, cell) in decoded.cells.values().zip(current.cells.values()) {
+      assert!((decoded.x - cell.x).abs() <= tolerance(X_RANGE, POSITION_BITS));
+      assert!((decoded.y - cell.y).abs() <= tolerance(Y_RANGE, POSITION_BITS));
+      assert_eq!(decoded.radius, cell.radius);
+    }
+  }
+
+  #[test]
+  fn positions_round_trip_within_tolerance() {
+    let max_error = tolerance(X_RANGE, POSITION_BITS);
+    assert!(max_error < 0.1, "position error {} is too big", max_error);
+
+    let steps = 10_000;
+    for i in 0..=steps {
+      let t = i as f32 / steps as f32;
+      let x = X_RANGE.0 + t * (X_RANGE.1 - X_RANGE.0);
+      let y = Y_RANGE.1 - t * (Y_RANGE.1 - Y_RANGE.0);
+      let state = CellState {
+        uid: i,
+        x,
+        y,
+        vx: VELOCITY_RANGE.0 + t * (VELOCITY_RANGE.1 - VELOCITY_RANGE.0),
+        vy: 0.0,
+        radius: 50.0,
+      };
+
+      let bytes = state.to_bytes().unwrap();
+      let (_, decoded) = CellState::from_bytes((bytes.as_ref(), 0)).unwrap();
+      assert!((decoded.x - x).abs() <= max_error, "{} decoded as {}", x, decoded.x);
+      assert!((decoded.y - y).abs() <= max_error, "{} decoded as {}", y, decoded.y);
+      assert!((decoded.vx - state.vx).abs() <= tolerance(VELOCITY_RANGE, VELOCITY_BITS));
+      assert_eq!(decoded.vy, 0.0);
+
+      // Joining players and inputs get the same precision.
+      let food = FoodInfo { id: i, x, y };
+      let bytes = food.to_bytes().unwrap();
+      let (_, decoded) = FoodInfo::from_bytes((bytes.as_ref(), 0)).unwrap();
+      assert!((decoded.x - x).abs() <= max_error && (decoded.y - y).abs() <= max_error);
+
+      let radius = t * RADIUS_RANGE.1;
+      let info = CellInfo {
+        uid: i,
+        owner: i,
+        x,
+        y,
+        radius,
+        name: CString::new("cell").unwrap(),
+        color: 0,
+      };
+      let bytes = info.to_bytes().unwrap();
+      let (_, decoded) = CellInfo::from_bytes((bytes.as_ref(), 0)).unwrap();
+      assert!((decoded.x - x).abs() <= max_error && (decoded.y - y).abs() <= max_error);
+      assert!((decoded.radius - radius).abs() <= tolerance(RADIUS_RANGE, POSITION_BITS));
+      assert_eq!(decoded.name, info.name);
+
+      for msg in [Message::SpawnFood(x, y, i), Message::MoveTo(x, y, i, i)] {
+        let bytes = msg.to_bytes().unwrap();
+        let (decoded_x, decoded_y) = match Message::try_from(bytes.as_ref()).unwrap() {
+          Message::SpawnFood(x, y, id) if id == i => (x, y),
+          Message::MoveTo(x, y, uid, seq) if (uid, seq) == (i, i) => (x, y),
+          decoded => panic!("{:?} decoded as {:?}", msg, decoded),
+        };
+        assert!((decoded_x - x).abs() <= max_error && (decoded_y - y).abs() <= max_error);
+      }
+    }
+  }
+
+  #[test]
+  fn out_of_range_values_are_clamped() {
+    let state = cell(0, X_RANGE.1 + 500.0, Y_RANGE.0 - 500.0, 50.0);
+    let bytes = state.to_bytes().unwrap();
+    let (_, decoded) = CellState::from_bytes((bytes.as_ref(), 0)).unwrap();
+    assert_eq!((decoded.x, decoded.y), (X_RANGE.1, Y_RANGE.0));
+  }
+
+  #[test]
+  fn quantized_cells_are_smaller() {
+    // uid and radius, plus the fixed-point position and velocity.
+    let bits = 32 + 2 * POSITION_BITS + 2 * VELOCITY_BITS + 32;
+    let bytes = cell(0, 1.0, 2.0, 50.0).to_bytes().unwrap();
+    assert_eq!(bytes.len(), bits.div_ceil(8));
+    assert!(bytes.len() < 6 * std::mem::size_of::<f32>());
+
+    // id and the fixed-point position.
+    let food = FoodInfo { id: 0, x: 1.0, y: 2.0 };
+    assert_eq!(food.to_bytes().unwrap().len(), (32 + 2 * POSITION_BITS) / 8);
+  }
+
+  #[test]
+  fn moving_pellets_are_sent_until_they_stop() {
+    let pellet = |x: f32, vx: f32| PelletState { id: 9, x, y: 0.0, vx, vy: 0.0 };
+    let mut base = snapshot(1, &[], &[]);
+    base.pellets.insert(9, pellet(10.0, 500.0));
+
+    let mut moved = snapshot(2, &[], &[]);
+    moved.pellets.insert(9, pellet(25.0, 0.0));
+    let delta = base.diff(&moved);
+    assert_eq!(delta.pellets, vec![pellet(25.0, 0.0)]);
+
+    let bytes = Message::Delta(delta).to_bytes().unwrap();
+    let Message::Delta(delta) = Message::try_from(bytes.as_ref()).unwrap() else {
+      panic!("expected a delta");
+    };
+    let decoded = base.apply(&delta);
+    assert!((decoded.pellets[&9].x - 25.0).abs() <= tolerance(X_RANGE, POSITION_BITS));
+    assert_eq!(decoded.pellets[&9].vx, 0.0);
+
+    let mut stopped = moved.clone();
+    stopped.seq = 3;
+    assert!(moved.diff(&stopped).pellets.is_empty());
+    stopped.pellets.clear();
+    assert_eq!(moved.diff(&stopped).removed_pellets, vec![9]);
+  }
+
+  #[test]
+  fn unchanged_snapshot_is_empty() {
+    let mut base = snapshot(3, &[cell(0, 1.0, 2.0, 50.0)], &[(0, 1.0, 1.0)]);
+    let virus = VirusState { id: 0, x: 5.0, y: 5.0, radius: 100.0 };
+    base.viruses.insert(0, virus);
+    let mut current = base.clone();
+    current.seq = 4;
+
+    let delta = base.diff(&current);
+    assert!(delta.cells.is_empty() && delta.removed_cells.is_empty());
+    assert!(delta.food.is_empty() && delta.removed_food.is_empty());
+    assert!(delta.pellets.is_empty() && delta.removed_pellets.is_empty());
+    assert!(delta.viruses.is_empty() && delta.removed_viruses.is_empty());
+    assert_eq!(base.apply(&delta), current);
+  }
+}
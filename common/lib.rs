@@ -1,39 +1,276 @@
+// deku's derive macros trip this lint on newer toolchains.
+#![allow(clippy::manual_div_ceil)]
+
 use deku::prelude::*;
+use std::ffi::CString;
+
+mod delta;
+mod movement;
+
+use delta::{read_fixed, write_fixed, RADIUS_RANGE, X_RANGE, Y_RANGE};
+
+pub use delta::{
+  dequantize, quantize, CellState, Delta, PelletState, Snapshot, VirusState, MAX_VELOCITY,
+  POSITION_BITS, VELOCITY_BITS,
+};
+pub use movement::{clamp_to_map, decelerate, Body, Steer, FRICTION, PLAYER_SPEED};
+
+// Bump whenever the encoding of `Message` changes, clients and servers
+// only talk to each other when it matches.
+pub const PROTOCOL_VERSION: u16 = 15;
 
 // How much of the map is visible to the player at once.
 pub const CAMERA_WIDTH: f32 = 1000.0;
 pub const CAMERA_HEIGHT: f32 = 1000.0;
 
+// Radius of a freshly spawned cell, it sees exactly one camera.
+const VIEW_BASE_RADIUS: f32 = 50.0;
+
+// Width and height of the area a cell of the given radius can see,
+// bigger cells see further.
+pub fn view_size(radius: f32) -> (f32, f32) {
+  let scale = (radius / VIEW_BASE_RADIUS).sqrt().max(1.0);
+  (CAMERA_WIDTH * scale, CAMERA_HEIGHT * scale)
+}
+
 // Total map size.
 pub const MAP_WIDTH: f32 = 10000.0;
 pub const MAP_HEIGHT: f32 = 10000.0;
 
+// Size of a pellet of ejected mass.
+pub const PELLET_RADIUS: f32 = 15.0;
+
 // Total number of food globules.
 pub const MAX_FOOD: usize = 1000;
 
+// Server simulation ticks per second.
+pub const TICK_RATE: u32 = 30;
+
+// Positions and radii are quantized like in deltas, see `POSITION_BITS`.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+pub struct FoodInfo {
+  pub id: u32,
+  #[deku(
+    reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *x, X_RANGE, POSITION_BITS)"
+  )]
+  pub x: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *y, Y_RANGE, POSITION_BITS)"
+  )]
+  pub y: f32,
+}
+
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+pub struct CellInfo {
+  pub uid: u32,
+  // uid of the player the cell belongs to, the same as `uid` for the
+  // cell a player spawns with.
+  pub owner: u32,
+  #[deku(
+    reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *x, X_RANGE, POSITION_BITS)"
+  )]
+  pub x: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *y, Y_RANGE, POSITION_BITS)"
+  )]
+  pub y: f32,
+  #[deku(
+    reader = "read_fixed(deku::rest, RADIUS_RANGE, POSITION_BITS)",
+    writer = "write_fixed(deku::output, *radius, RADIUS_RANGE, POSITION_BITS)"
+  )]
+  pub radius: f32,
+  pub name: CString,
+  // 0xRRGGBB
+  pub color: u32,
+}
+
+// Id of `Message::Hello`.
+pub const HELLO_ID: u8 = 9;
+
+// Every version of the `Hello` starts like this, servers check the version
+// before reading the rest, which other versions may lay out differently.
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+pub struct HelloHeader {
+  pub id: u8,
+  pub protocol_version: u16,
+}
+
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
 #[deku(type = "u8")]
 pub enum Message {
+  // Food is not sent on the wire, the client derives it from each
+  // `Delta` along with everything else that changed.
   #[deku(id = "0")]
-  SpawnFood(f32, f32),
-  #[deku(id = "1")]
-  // x, y, uid
-  NewPlayer(f32, f32, u32),
+  // x, y, id
+  SpawnFood(
+    #[deku(
+      reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+      writer = "write_fixed(deku::output, *field_0, X_RANGE, POSITION_BITS)"
+    )]
+    f32,
+    #[deku(
+      reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+      writer = "write_fixed(deku::output, *field_1, Y_RANGE, POSITION_BITS)"
+    )]
+    f32,
+    u32,
+  ),
+  // Id 1 was `NewPlayer`, cells are spawned when they enter a view.
   #[deku(id = "2")]
-  // x, y, uid
-  MovePlayer(f32, f32, u32),
+  // x, y, uid, seq
+  // Inputs are numbered so that the client knows which ones the server
+  // applied, see `Snapshot::input`.
+  MovePlayer(f32, f32, u32, u32),
   #[deku(id = "3")]
-  // x, y, uid
-  Start(f32, f32, u32),
+  // uid, resume token
+  // The player joined, its cells come into view like everyone else's.
+  // Presenting the token in the next `Hello` resumes the player.
+  Start(u32, u64),
+  // Ids 4 to 6 were cell and food updates, the client derives them from
+  // deltas.
+  #[deku(id = "7")]
+  // uid
+  // The last cell of a player was eaten, the player is out of the game.
+  PlayerDied(u32),
+  #[deku(id = "8")]
+  // uid
+  // The player disconnected, sent for each of its cells.
+  PlayerLeft(u32),
+  #[deku(id = "HELLO_ID")]
+  // First message a client sends after connecting.
+  Hello {
+    protocol_version: u16,
+    client_name: CString,
+    // Token from the last `Start`, 0 to join as a new player.
+    resume_token: u64,
+  },
+  #[deku(id = "10")]
+  // The server's answer to a compatible `Hello`.
+  Welcome {
+    uid: u32,
+    server_version: u16,
+    // width, height
+    map_size: (f32, f32),
+  },
+  #[deku(id = "11")]
+  // Everything a joining player needs to know about the world, use
+  // `Message::world_snapshot` to build it.
+  WorldSnapshot {
+    // width, height
+    map_size: (f32, f32),
+    food_count: u16,
+    #[deku(count = "food_count")]
+    food: Vec<FoodInfo>,
+    cell_count: u16,
+    #[deku(count = "cell_count")]
+    cells: Vec<CellInfo>,
+  },
+  #[deku(id = "12")]
+  // State of the world relative to a snapshot the client acknowledged,
+  // sent by the server every tick.
+  Delta(Delta),
+  #[deku(id = "13")]
+  // seq
+  // Sent by the client for every snapshot it reconstructed.
+  Ack(u32),
+  #[deku(id = "14")]
+  // A cell came into the player's view.
+  EnterView(CellInfo),
+  #[deku(id = "15")]
+  // uid
+  // A cell left the player's view, it is still in the game.
+  LeaveView(u32),
+  #[deku(id = "16")]
+  // uid, dir_x, dir_y
+  // Splits every cell of the player that is big enough in two, the new
+  // halves are launched toward the direction.
+  Split(u32, f32, f32),
+  #[deku(id = "17")]
+  // uid
+  // A cell was eaten or merged back into another one, its player still
+  // has other cells. Losing the last cell is a `PlayerDied`.
+  CellRemoved(u32),
+  #[deku(id = "18")]
+  // uid, dir_x, dir_y
+  // Every cell of the player that is big enough ejects a pellet of its
+  // mass toward the direction.
+  Eject(u32, f32, f32),
+  // Ids 19 to 22 were pellet and virus updates, the client derives them
+  // from deltas.
+  #[deku(id = "23")]
+  // x, y, uid, seq
+  // Every cell of the player heads toward the point on the map, slowing
+  // down as it gets close. Replaced by the next `MovePlayer`.
+  MoveTo(
+    #[deku(
+      reader = "read_fixed(deku::rest, X_RANGE, POSITION_BITS)",
+      writer = "write_fixed(deku::output, *field_0, X_RANGE, POSITION_BITS)"
+    )]
+    f32,
+    #[deku(
+      reader = "read_fixed(deku::rest, Y_RANGE, POSITION_BITS)",
+      writer = "write_fixed(deku::output, *field_1, Y_RANGE, POSITION_BITS)"
+    )]
+    f32,
+    u32,
+    u32,
+  ),
+  // Id 24 was `InputAck`, the client derives it from deltas.
+}
+
+// Several messages are sent in a single frame by writing them back to
+// back, every message knows its own length.
+pub fn encode_batch(msgs: &[Message]) -> Result<Vec<u8>, DekuError> {
+  let mut bytes = Vec::new();
+  for msg in msgs {
+    bytes.extend(msg.to_bytes()?);
+  }
+  Ok(bytes)
+}
+
+pub fn decode_batch(mut bytes: &[u8]) -> Result<Vec<Message>, DekuError> {
+  let mut msgs = Vec::new();
+  while !bytes.is_empty() {
+    let ((rest, offset), msg) = Message::from_bytes((bytes, 0))?;
+    msgs.push(msg);
+    // Messages are padded to a whole byte.
+    bytes = if offset == 0 { rest } else { &rest[1..] };
+  }
+  Ok(msgs)
 }
 
 impl Message {
+  pub fn world_snapshot(map_size: (f32, f32), food: Vec<FoodInfo>, cells: Vec<CellInfo>) -> Self {
+    Message::WorldSnapshot {
+      map_size,
+      food_count: food.len().try_into().expect("too much food for a snapshot"),
+      food,
+      cell_count: cells.len().try_into().expect("too many cells for a snapshot"),
+      cells,
+    }
+  }
+
   pub fn uid(&self) -> Option<u32> {
     match self {
-      Message::NewPlayer(_, _, uid) => Some(*uid),
-      Message::MovePlayer(_, _, uid) => Some(*uid),
-      Message::Start(_, _, uid) => Some(*uid),
-      Message::SpawnFood(_, _) => None,
+      Message::EnterView(info) => Some(info.uid),
+      Message::MovePlayer(_, _, uid, _) | Message::MoveTo(_, _, uid, _) => Some(*uid),
+      Message::Start(uid, _) => Some(*uid),
+      Message::PlayerDied(uid) => Some(*uid),
+      Message::PlayerLeft(uid) => Some(*uid),
+      Message::LeaveView(uid) => Some(*uid),
+      Message::Split(uid, _, _) => Some(*uid),
+      Message::CellRemoved(uid) => Some(*uid),
+      Message::Eject(uid, _, _) => Some(*uid),
+      Message::Welcome { uid, .. } => Some(*uid),
+      Message::SpawnFood(_, _, _)
+      | Message::Hello { .. }
+      | Message::WorldSnapshot { .. }
+      | Message::Delta(_)
+      | Message::Ack(_) => None,
     }
   }
 }
@@ -0,0 +1,209 @@
+// How cells move. The server steps every cell with this once per tick and
+// clients step their own cells the same way to predict where the server
+// will put them, so the same inputs have to give the same positions.
+
+use crate::{MAP_HEIGHT, MAP_WIDTH};
+
+// Player movement speed in 'pixels/second'.
+pub const PLAYER_SPEED: f32 = 300.0;
+
+// Launched things slow down by `FRICTION` 'pixels/second²'.
+pub const FRICTION: f32 = 1000.0;
+
+// What a player's cells follow, the latest input of the player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Steer {
+  // dir_x, dir_y
+  Direction(f32, f32),
+  // x, y
+  // Cells head toward the point and slow down once it is within their
+  // radius.
+  Target(f32, f32),
+}
+
+impl Default for Steer {
+  fn default() -> Self {
+    Steer::Direction(0.0, 0.0)
+  }
+}
+
+impl Steer {
+  // Direction a cell at the position moves in, scaled by how fast it
+  // moves. Clients can send anything, it is never longer than 1.
+  pub fn direction(&self, x: f32, y: f32, radius: f32) -> (f32, f32) {
+    match *self {
+      Steer::Direction(dx, dy) => {
+        let len = (dx * dx + dy * dy).sqrt();
+        if !len.is_finite() {
+          (0.0, 0.0)
+        } else if len > 1.0 {
+          (dx / len, dy / len)
+        } else {
+          (dx, dy)
+        }
+      }
+      Steer::Target(tx, ty) => {
+        let (dx, dy) = (tx - x, ty - y);
+        let distance = (dx * dx + dy * dy).sqrt();
+        if distance > 0.0 && distance.is_finite() {
+          let speed = (distance / radius).min(1.0);
+          (dx / distance * speed, dy / distance * speed)
+        } else {
+          (0.0, 0.0)
+        }
+      }
+    }
+  }
+}
+
+// Where a cell is, and the velocity it was launched with on top of its
+// own movement.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Body {
+  pub x: f32,
+  pub y: f32,
+  pub boost_x: f32,
+  pub boost_y: f32,
+  pub radius: f32,
+}
+
+impl Body {
+  // A body moving with the velocity, the launch is whatever the steering
+  // does not account for.
+  pub fn with_velocity(x: f32, y: f32, vx: f32, vy: f32, radius: f32, steer: Steer) -> Self {
+    let (dir_x, dir_y) = steer.direction(x, y, radius);
+    Body {
+      x,
+      y,
+      boost_x: vx - dir_x * PLAYER_SPEED,
+      boost_y: vy - dir_y * PLAYER_SPEED,
+      radius,
+    }
+  }
+
+  pub fn velocity(&self, steer: Steer) -> (f32, f32) {
+    let (dir_x, dir_y) = steer.direction(self.x, self.y, self.radius);
+    (
+      dir_x * PLAYER_SPEED + self.boost_x,
+      dir_y * PLAYER_SPEED + self.boost_y,
+    )
+  }
+
+  // Moves the body by `dt` seconds, it stays inside the map.
+  pub fn step(&mut self, steer: Steer, dt: f32) {
+    let (vx, vy) = self.velocity(steer);
+    self.x += vx * dt;
+    self.y += vy * dt;
+    clamp_to_map(&mut self.x, &mut self.y, self.radius);
+    decelerate(&mut self.boost_x, &mut self.boost_y, dt);
+  }
+}
+
+// Keeps a circle inside the map, which is centered around the origin.
+pub fn clamp_to_map(x: &mut f32, y: &mut f32, radius: f32) {
+  let (half_width, half_height) = (MAP_WIDTH / 2.0 - radius, MAP_HEIGHT / 2.0 - radius);
+  *x = x.clamp(-half_width, half_width);
+  *y = y.clamp(-half_height, half_height);
+}
+
+// Slows a launched velocity down by `FRICTION`, without reversing it.
+pub fn decelerate(vx: &mut f32, vy: &mut f32, dt: f32) {
+  let speed = (*vx * *vx + *vy * *vy).sqrt();
+  if speed > 0.0 {
+    let factor = (speed - FRICTION * dt).max(0.0) / speed;
+    *vx *= factor;
+    *vy *= factor;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DT: f32 = 1.0 / crate::TICK_RATE as f32;
+
+  // Inputs that change every few ticks, steering both ways.
+  fn inputs() -> Vec<Steer> {
+    (0..300)
+      .map(|i| match i / 20 % 3 {
+        0 => Steer::Direction(1.0, 0.5),
+        1 => Steer::Target(200.0, -300.0),
+        _ => Steer::Direction(-3.0, 4.0),
+      })
+      .collect()
+  }
+
+  // The body after every input.
+  fn run(mut body: Body, inputs: &[Steer]) -> Vec<Body> {
+    inputs
+      .iter()
+      .map(|steer| {
+        body.step(*steer, DT);
+        body
+      })
+      .collect()
+  }
+
+  fn launched() -> Body {
+    Body { x: 10.0, y: -20.0, boost_x: 700.0, boost_y: 0.0, radius: 50.0 }
+  }
+
+  #[test]
+  fn same_inputs_give_the_same_positions() {
+    let inputs = inputs();
+    let bodies = run(launched(), &inputs);
+    assert_eq!(bodies, run(launched(), &inputs));
+
+    // Starting over from any step and replaying the rest is how clients
+    // predict, it has to end up in exactly the same place.
+    for from in [0, 10, 150, 298] {
+      assert_eq!(run(bodies[from], &inputs[from + 1..]), bodies[from + 1..]);
+    }
+  }
+
+  #[test]
+  fn movement_is_never_faster_than_player_speed() {
+    let body = Body { radius: 50.0, ..Default::default() };
+    let speed = |steer| {
+      let (vx, vy) = body.velocity(steer);
+      (vx * vx + vy * vy).sqrt()
+    };
+    assert_eq!(speed(Steer::Direction(3.0, 4.0)), PLAYER_SPEED);
+    assert_eq!(speed(Steer::Direction(0.0, 0.5)), PLAYER_SPEED / 2.0);
+    assert_eq!(speed(Steer::Direction(f32::NAN, 1.0)), 0.0);
+    assert_eq!(speed(Steer::Target(0.0, 5000.0)), PLAYER_SPEED);
+    // Slower once the target is within the radius, still when on it.
+    assert_eq!(speed(Steer::Target(25.0, 0.0)), PLAYER_SPEED / 2.0);
+    assert_eq!(speed(Steer::Target(0.0, 0.0)), 0.0);
+  }
+
+  #[test]
+  fn launches_slow_down_without_reversing() {
+    let mut body = launched();
+    body.step(Steer::default(), DT);
+    assert_eq!(body.boost_x, 700.0 - FRICTION * DT);
+
+    body.step(Steer::default(), 1.0);
+    assert_eq!((body.boost_x, body.boost_y), (0.0, 0.0));
+  }
+
+  #[test]
+  fn launch_is_recovered_from_the_velocity() {
+    let body = launched();
+    let steer = Steer::Target(-100.0, 40.0);
+    let (vx, vy) = body.velocity(steer);
+
+    let recovered = Body::with_velocity(body.x, body.y, vx, vy, body.radius, steer);
+    assert!((recovered.boost_x - body.boost_x).abs() < 1e-3);
+    assert!((recovered.boost_y - body.boost_y).abs() < 1e-3);
+  }
+
+  #[test]
+  fn bodies_stay_inside_the_map() {
+    let mut body = launched();
+    for _ in 0..(MAP_WIDTH / PLAYER_SPEED / DT) as usize {
+      body.step(Steer::Direction(1.0, -1.0), DT);
+    }
+    assert_eq!((body.x, body.y), (MAP_WIDTH / 2.0 - 50.0, -MAP_HEIGHT / 2.0 + 50.0));
+  }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+// Axis aligned rectangle, used for what a player can see.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub max_x: f32,
+    pub max_y: f32,
+}
+
+impl Rect {
+    pub fn around(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            min_x: x - width / 2.0,
+            min_y: y - height / 2.0,
+            max_x: x + width / 2.0,
+            max_y: y + height / 2.0,
+        }
+    }
+
+    pub fn grow(&self, margin: f32) -> Self {
+        Self {
+            min_x: self.min_x - margin,
+            min_y: self.min_y - margin,
+            max_x: self.max_x + margin,
+            max_y: self.max_y + margin,
+        }
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        (self.min_x..=self.max_x).contains(&x) && (self.min_y..=self.max_y).contains(&y)
+    }
+
+    // Whether any part of a circle is inside, close enough for culling.
+    pub fn overlaps(&self, x: f32, y: f32, radius: f32) -> bool {
+        x + radius >= self.min_x
+            && x - radius <= self.max_x
+            && y + radius >= self.min_y
+            && y - radius <= self.max_y
+    }
+}
+
+// An item and its position.
+type Entry<T> = (T, f32, f32);
+
+// Uniform grid over the map, items are bucketed by the square their
+// position falls in so that looking up what is inside a rectangle only
+// visits the squares it covers. Buckets are created on demand, so the
+// grid is not bounded by the map.
+pub struct Grid<T> {
+    cell_size: f32,
+    buckets: HashMap<(i32, i32), Vec<Entry<T>>>,
+}
+
+impl<T: Copy + PartialEq> Grid<T> {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn key(&self, x: f32, y: f32) -> (i32, i32) {
+        (
+            (x / self.cell_size).floor() as i32,
+            (y / self.cell_size).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, item: T, x: f32, y: f32) {
+        let key = self.key(x, y);
+        self.buckets.entry(key).or_default().push((item, x, y));
+    }
+
+    // `x` and `y` have to be where the item was inserted at. Returns
+    // false if it was not there.
+    pub fn remove(&mut self, item: T, x: f32, y: f32) -> bool {
+        let key = self.key(x, y);
+        let Some(bucket) = self.buckets.get_mut(&key) else {
+            return false;
+        };
+        let Some(i) = bucket.iter().position(|(other, _, _)| *other == item) else {
+            return false;
+        };
+        bucket.swap_remove(i);
+        if bucket.is_empty() {
+            self.buckets.remove(&key);
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+    }
+
+    // Every item whose position is inside `rect`.
+    pub fn query(&self, rect: Rect) -> impl Iterator<Item = Entry<T>> + '_ {
+        let (min_x, min_y) = self.key(rect.min_x, rect.min_y);
+        let (max_x, max_y) = self.key(rect.max_x, rect.max_y);
+        (min_x..=max_x)
+            .flat_map(move |i| (min_y..=max_y).filter_map(move |j| self.buckets.get(&(i, j))))
+            .flatten()
+            .copied()
+            .filter(move |(_, x, y)| rect.contains(*x, *y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn query_matches_a_linear_scan() {
+        let mut rng = rand::thread_rng();
+        let mut grid = Grid::new(100.0);
+        let points: Vec<(u32, f32, f32)> = (0..1000)
+            .map(|id| {
+                (
+                    id,
+                    rng.gen_range(-1000.0..1000.0),
+                    rng.gen_range(-1000.0..1000.0),
+                )
+            })
+            .collect();
+        for (id, x, y) in &points {
+            grid.insert(*id, *x, *y);
+        }
+
+        for _ in 0..100 {
+            let (x, y) = (
+                rng.gen_range(-1000.0..1000.0),
+                rng.gen_range(-1000.0..1000.0),
+            );
+            let rect = Rect::around(x, y, rng.gen_range(0.0..500.0), rng.gen_range(0.0..500.0));
+
+            let mut found: Vec<_> = grid.query(rect).map(|(id, _, _)| id).collect();
+            found.sort();
+            let expected: Vec<_> = points
+                .iter()
+                .filter(|(_, x, y)| rect.contains(*x, *y))
+                .map(|(id, _, _)| *id)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn removed_items_are_not_found() {
+        let mut grid = Grid::new(100.0);
+        grid.insert(1, -50.0, 50.0);
+        grid.insert(2, -50.0, 50.0);
+
+        assert!(grid.remove(1, -50.0, 50.0));
+        assert!(!grid.remove(1, -50.0, 50.0));
+        let rect = Rect::around(0.0, 0.0, 200.0, 200.0);
+        assert_eq!(grid.query(rect).collect::<Vec<_>>(), vec![(2, -50.0, 50.0)]);
+    }
+}
@@ -1,9 +1,14 @@
-use common::Message;
+use common::{
+    clamp_to_map, decelerate, CellInfo, CellState, FoodInfo, HelloHeader, Message, PelletState,
+    Snapshot, Steer, VirusState, HELLO_ID, MAP_HEIGHT, MAP_WIDTH, MAX_FOOD, PELLET_RADIUS,
+    PROTOCOL_VERSION,
+};
 use deku::prelude::*;
 use fastwebsockets::upgrade;
 use fastwebsockets::Frame;
 use fastwebsockets::OpCode;
 use fastwebsockets::WebSocket;
+use grid::{Grid, Rect};
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
 use hyper::upgrade::Upgraded;
@@ -11,10 +16,17 @@ use hyper::Body;
 use hyper::Request;
 use hyper::Response;
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
 use std::future::Future;
 use std::rc::Rc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast::{self, channel};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::MissedTickBehavior;
+
+mod grid;
 
 #[derive(Clone)]
 struct SpawnExecutor;
@@ -29,42 +41,954 @@ where
     }
 }
 
-// Generate random food x and y coordinates.
+// Generate random food x and y coordinates anywhere on the map,
+// which is centered around the origin.
 fn gen_food() -> (f32, f32) {
     use rand::Rng;
 
     let mut rng = rand::thread_rng();
-    let x = rng.gen_range(-500.0..500.0);
-    let y = rng.gen_range(-500.0..500.0);
+    let x = rng.gen_range(-MAP_WIDTH / 2.0..MAP_WIDTH / 2.0);
+    let y = rng.gen_range(-MAP_HEIGHT / 2.0..MAP_HEIGHT / 2.0);
     (x, y)
 }
 
+// How many eaten food globules grow back each tick.
+const FOOD_SPAWN_PER_TICK: usize = 10;
+
 fn msg_to_frame(msg: Message) -> Vec<u8> {
     msg.to_bytes().unwrap()
 }
 
+fn batch_frame(msgs: &[Message]) -> Frame<'static> {
+    let payload = common::encode_batch(msgs).unwrap();
+    Frame::new(true, OpCode::Binary, None, payload.into())
+}
+
+// Mass a player spawns with, and how much each food globule adds.
+const START_MASS: f32 = 25.0;
+const FOOD_MASS: f32 = 1.0;
+
+// How many times bigger a cell has to be to eat another one.
+const EAT_RATIO: f32 = 1.25;
+
+// Cells need this much mass to split, and a player can't have more
+// than `MAX_CELLS` cells.
+const MIN_SPLIT_MASS: f32 = 2.0 * START_MASS;
+const MAX_CELLS: usize = 16;
+
+// Speed the new half is launched at when splitting, in 'pixels/second'.
+const SPLIT_SPEED: f32 = 700.0;
+
+// Seconds after splitting before cells of a player merge back together.
+const MERGE_COOLDOWN: f32 = 10.0;
+
+// Mass a cell loses when ejecting a pellet, which is what the pellet is
+// worth to whoever eats it. Cells never eject below their start mass.
+const EJECT_MASS: f32 = 4.0;
+const MIN_EJECT_MASS: f32 = START_MASS + EJECT_MASS;
+
+// Speed pellets are ejected at, in 'pixels/second'.
+const EJECT_SPEED: f32 = 800.0;
+
+// Viruses are kept at `VIRUS_COUNT` by spawning new ones, feeding them
+// can add more up to `MAX_VIRUSES`.
+const VIRUS_COUNT: usize = 20;
+const MAX_VIRUSES: usize = 50;
+const VIRUS_MASS: f32 = 100.0;
+
+// Pellets a virus takes before it shoots a new virus, which is launched
+// at `VIRUS_SHOT_SPEED` 'pixels/second'.
+const VIRUS_FEED_COUNT: u32 = 7;
+const VIRUS_SHOT_SPEED: f32 = 800.0;
+
+// Pieces a cell breaks into when it pops on a virus, on top of itself.
+const POP_PIECES: usize = 8;
+
+// Seconds the cells of a disconnected player wait for it to come back
+// with its resume token before they are removed.
+const RESUME_GRACE: f32 = 30.0;
+
+// Inputs are applied one per tick, a player that got this far ahead drops
+// its oldest ones instead of lagging behind.
+const MAX_QUEUED_INPUTS: usize = 8;
+
+fn mass_to_radius(mass: f32) -> f32 {
+    10.0 * mass.sqrt()
+}
+
+// Colors players are randomly given when joining, as 0xRRGGBB.
+const PLAYER_COLORS: [u32; 8] = [
+    0xe6194b, 0x3cb44b, 0xffe119, 0x4363d8, 0xf58231, 0x911eb4, 0x46f0f0, 0xf032e6,
+];
+
+// A pellet of ejected mass, that anyone can eat.
+struct Pellet {
+    x: f32,
+    y: f32,
+    vx: f32,
+    vy: f32,
+}
+
+// A spiky hazard, cells big enough to eat it pop into many pieces.
+struct Virus {
+    x: f32,
+    y: f32,
+    mass: f32,
+    radius: f32,
+    // Moving after being shot by another virus.
+    vx: f32,
+    vy: f32,
+    // Pellets it took since it last shot.
+    fed: u32,
+}
+
+impl Virus {
+    fn new(x: f32, y: f32) -> Self {
+        Self {
+            x,
+            y,
+            mass: VIRUS_MASS,
+            radius: mass_to_radius(VIRUS_MASS),
+            vx: 0.0,
+            vy: 0.0,
+            fed: 0,
+        }
+    }
+
+    // Takes in a pellet moving in the direction, returns the new virus
+    // once it had enough.
+    fn feed(&mut self, dir_x: f32, dir_y: f32) -> Option<Virus> {
+        self.fed += 1;
+        self.mass += EJECT_MASS;
+        self.radius = mass_to_radius(self.mass);
+        if self.fed < VIRUS_FEED_COUNT {
+            return None;
+        }
+
+        self.fed = 0;
+        self.mass = VIRUS_MASS;
+        self.radius = mass_to_radius(VIRUS_MASS);
+        let mut virus = Virus::new(
+            self.x + dir_x * 2.0 * self.radius,
+            self.y + dir_y * 2.0 * self.radius,
+        );
+        virus.vx = dir_x * VIRUS_SHOT_SPEED;
+        virus.vy = dir_y * VIRUS_SHOT_SPEED;
+        Some(virus)
+    }
+}
+
+// One of a player's cells, every player in the game has at least one.
 struct Player {
     x: f32,
     y: f32,
+    mass: f32,
     radius: f32,
+    // Latest input of the client, all of the player's cells follow it.
+    steer: Steer,
+    // Velocity the cell was launched with, on top of its movement.
+    boost_x: f32,
+    boost_y: f32,
+    // Seconds left before the cell can merge with the player's others.
+    merge_timer: f32,
     uid: u32,
+    owner: u32,
+    name: String,
+    color: u32,
+}
+
+impl Player {
+    fn info(&self) -> CellInfo {
+        CellInfo {
+            uid: self.uid,
+            owner: self.owner,
+            x: self.x,
+            y: self.y,
+            radius: self.radius,
+            name: CString::new(self.name.clone()).unwrap_or_default(),
+            color: self.color,
+        }
+    }
+
+    // What moves, see `common::Body`.
+    fn body(&self) -> common::Body {
+        common::Body {
+            x: self.x,
+            y: self.y,
+            boost_x: self.boost_x,
+            boost_y: self.boost_y,
+            radius: self.radius,
+        }
+    }
+
+    // A cell can eat another one that is sufficiently smaller and
+    // whose center it covers.
+    fn can_eat(&self, other: &Player) -> bool {
+        let (dx, dy) = (other.x - self.x, other.y - self.y);
+        self.mass >= other.mass * EAT_RATIO && dx * dx + dy * dy < self.radius * self.radius
+    }
+
+    // Normalized direction to launch things at, where the cell is heading
+    // if there is no direction, and up when it stands still.
+    fn aim(&self, x: f32, y: f32) -> (f32, f32) {
+        let heading = self.steer.direction(self.x, self.y, self.radius);
+        [(x, y), heading, (0.0, 1.0)]
+            .into_iter()
+            .find_map(|(x, y)| {
+                let len = (x * x + y * y).sqrt();
+                (len > 0.0 && len.is_finite()).then(|| (x / len, y / len))
+            })
+            .unwrap()
+    }
+
+    // Ejects a pellet toward the direction, from the edge of the cell.
+    fn eject(&mut self, dir_x: f32, dir_y: f32) -> Pellet {
+        self.mass -= EJECT_MASS;
+        self.radius = mass_to_radius(self.mass);
+        let distance = self.radius + PELLET_RADIUS;
+        Pellet {
+            x: self.x + dir_x * distance,
+            y: self.y + dir_y * distance,
+            vx: dir_x * EJECT_SPEED,
+            vy: dir_y * EJECT_SPEED,
+        }
+    }
+
+    // Splits off a piece of `mass`, launched toward the direction.
+    fn split(&mut self, uid: u32, mass: f32, dir_x: f32, dir_y: f32) -> Player {
+        self.mass -= mass;
+        self.radius = mass_to_radius(self.mass);
+        self.merge_timer = MERGE_COOLDOWN;
+        // Right next to the cell, so they don't start overlapping.
+        let distance = self.radius + mass_to_radius(mass);
+        Player {
+            x: self.x + dir_x * distance,
+            y: self.y + dir_y * distance,
+            mass,
+            radius: mass_to_radius(mass),
+            boost_x: dir_x * SPLIT_SPEED,
+            boost_y: dir_y * SPLIT_SPEED,
+            uid,
+            name: self.name.clone(),
+            ..*self
+        }
+    }
 }
 
+// Size of the squares of the spatial indices, about the smallest view.
+const GRID_CELL_SIZE: f32 = 250.0;
+
 struct Game {
-    food: Vec<(f32, f32)>,
+    // Food globules by id.
+    food: HashMap<u32, (f32, f32)>,
+    next_food_id: u32,
     cells: Vec<Player>,
-    incoming: broadcast::Sender<Message>,
-    broadcast: broadcast::Sender<Message>,
+    // Ejected pellets by id.
+    pellets: HashMap<u32, Pellet>,
+    next_pellet_id: u32,
+    // Viruses by id, there are few of them so they are not indexed.
+    viruses: HashMap<u32, Virus>,
+    next_virus_id: u32,
+    // Spatial indices of `food` and `pellets` by id and `cells` by
+    // index, kept up to date with every change.
+    food_grid: Grid<u32>,
+    pellet_grid: Grid<u32>,
+    cell_grid: Grid<usize>,
+    // Of every cell, views need to reach that far past their edges.
+    max_radius: f32,
+    // Inputs received from each player and not applied yet, oldest first.
+    queued_inputs: HashMap<u32, VecDeque<(u32, Steer)>>,
+    // Sequence number of the input each player's cells moved with in the
+    // last tick.
+    inputs: HashMap<u32, u32>,
+    // Players whose connection dropped, with the seconds left until they
+    // are removed.
+    away: HashMap<u32, f32>,
+    // The player each resume token resumes, one per player.
+    tokens: HashMap<u64, u32>,
+    // Connected players, with what closes their connection once another
+    // one resumes them.
+    connections: HashMap<u32, Rc<Notify>>,
+    // Next uid to hand out, uids are never reused while in use.
+    next_uid: u32,
+    // Inputs and leaving players, every one of them has to reach the game
+    // loop so the channel is not bounded like the broadcast.
+    incoming: mpsc::UnboundedSender<Message>,
+    // Everything that happened during a tick is broadcast at once.
+    broadcast: broadcast::Sender<Vec<Message>>,
 }
 
 impl Game {
-    fn new(incoming: broadcast::Sender<Message>, broadcast: broadcast::Sender<Message>) -> Self {
-        let food = (0..100).map(|_| gen_food()).collect();
-        Self {
-            food,
+    fn new(
+        incoming: mpsc::UnboundedSender<Message>,
+        broadcast: broadcast::Sender<Vec<Message>>,
+    ) -> Self {
+        let mut game = Self {
+            food: HashMap::new(),
+            next_food_id: 0,
             cells: Vec::new(),
+            pellets: HashMap::new(),
+            next_pellet_id: 0,
+            viruses: HashMap::new(),
+            next_virus_id: 0,
+            food_grid: Grid::new(GRID_CELL_SIZE),
+            pellet_grid: Grid::new(GRID_CELL_SIZE),
+            cell_grid: Grid::new(GRID_CELL_SIZE),
+            max_radius: 0.0,
+            queued_inputs: HashMap::new(),
+            inputs: HashMap::new(),
+            away: HashMap::new(),
+            tokens: HashMap::new(),
+            connections: HashMap::new(),
+            next_uid: 0,
             incoming,
             broadcast,
+        };
+        for _ in 0..MAX_FOOD {
+            game.spawn_food();
+        }
+        for _ in 0..VIRUS_COUNT {
+            let (x, y) = gen_food();
+            game.add_virus(Virus::new(x, y));
+        }
+        game
+    }
+
+    // Players and cells share uids, a player's uid is the one of the
+    // cell it spawned with.
+    fn next_uid(&mut self) -> u32 {
+        let mut uid = self.next_uid;
+        // Only matters once the counter wraps around.
+        while self.uid_in_use(uid) {
+            uid = uid.wrapping_add(1);
+        }
+        self.next_uid = uid.wrapping_add(1);
+        uid
+    }
+
+    // Eaten players keep their uid while they are still connected, and so
+    // do players that can still resume.
+    fn uid_in_use(&self, uid: u32) -> bool {
+        self.cells.iter().any(|cell| cell.uid == uid || cell.owner == uid)
+            || self.connections.contains_key(&uid)
+            || self.away.contains_key(&uid)
+            || self.tokens.values().any(|owner| *owner == uid)
+    }
+
+    // Adds a new player to the game and returns its uid.
+    fn add_player(&mut self, name: String) -> u32 {
+        use rand::seq::SliceRandom;
+
+        let uid = self.next_uid();
+        self.cells.push(Player {
+            x: 0.0,
+            y: 0.0,
+            mass: START_MASS,
+            radius: mass_to_radius(START_MASS),
+            steer: Steer::default(),
+            boost_x: 0.0,
+            boost_y: 0.0,
+            merge_timer: 0.0,
+            uid,
+            owner: uid,
+            name,
+            color: *PLAYER_COLORS.choose(&mut rand::thread_rng()).unwrap(),
+        });
+        self.index_cells();
+        uid
+    }
+
+    // Returns the uids of the player's cells, none if it was not in the
+    // game anymore, eg: it was eaten.
+    fn remove_player(&mut self, uid: u32) -> Vec<u32> {
+        let mut removed = Vec::new();
+        self.cells.retain(|cell| {
+            if cell.owner == uid {
+                removed.push(cell.uid);
+            }
+            cell.owner != uid
+        });
+        self.queued_inputs.remove(&uid);
+        self.inputs.remove(&uid);
+        self.away.remove(&uid);
+        self.tokens.retain(|_, owner| *owner != uid);
+        self.index_cells();
+        removed
+    }
+
+    // The player is playing on a new connection, the one it had before is
+    // told to close. The client numbers its inputs from the start again.
+    fn connect(&mut self, uid: u32) -> Rc<Notify> {
+        self.queued_inputs.remove(&uid);
+        self.inputs.remove(&uid);
+        let closed = Rc::new(Notify::new());
+        if let Some(old) = self.connections.insert(uid, closed.clone()) {
+            old.notify_one();
+        }
+        closed
+    }
+
+    // The connection closed, returns whether it was still the player's.
+    fn disconnect(&mut self, uid: u32, closed: &Rc<Notify>) -> bool {
+        match self.connections.get(&uid) {
+            Some(current) if Rc::ptr_eq(current, closed) => {
+                self.connections.remove(&uid);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // The player's connection dropped, its cells stop and wait for it to
+    // come back. Eaten players are removed right away.
+    fn leave(&mut self, uid: u32) {
+        // It already did before the game loop got here.
+        if self.connections.contains_key(&uid) {
+            return;
+        }
+        self.queued_inputs.remove(&uid);
+        let mut owned = false;
+        for cell in self.cells.iter_mut().filter(|cell| cell.owner == uid) {
+            cell.steer = Steer::default();
+            owned = true;
+        }
+        if owned {
+            self.away.insert(uid, RESUME_GRACE);
+        } else {
+            self.remove_player(uid);
+        }
+    }
+
+    // Hands out a new resume token for the player, the previous one stops
+    // working.
+    fn issue_token(&mut self, uid: u32) -> u64 {
+        self.tokens.retain(|_, owner| *owner != uid);
+        // 0 stands for no token.
+        let token = loop {
+            let token = rand::random();
+            if token != 0 && !self.tokens.contains_key(&token) {
+                break token;
+            }
+        };
+        self.tokens.insert(token, uid);
+        token
+    }
+
+    // Returns the uid of the player the token belongs to, if it still has
+    // cells. It is back in the game, even if its old connection did not
+    // look closed yet.
+    fn resume(&mut self, token: u64) -> Option<u32> {
+        let uid = *self.tokens.get(&token)?;
+        if !self.cells.iter().any(|cell| cell.owner == uid) {
+            return None;
+        }
+        self.away.remove(&uid);
+        Some(uid)
+    }
+
+    // Removes the players that stayed away for too long, returns the
+    // uids of their cells.
+    fn expire_away(&mut self, dt: f32) -> Vec<u32> {
+        let mut expired = Vec::new();
+        self.away.retain(|uid, left| {
+            *left -= dt;
+            if *left <= 0.0 {
+                expired.push(*uid);
+            }
+            *left > 0.0
+        });
+        expired
+            .into_iter()
+            .flat_map(|uid| self.remove_player(uid))
+            .collect()
+    }
+
+    fn cell(&self, uid: u32) -> Option<&Player> {
+        self.cells.iter().find(|cell| cell.uid == uid)
+    }
+
+    // What the player can see, centered on its cells and growing with
+    // their total mass. None once all of them were eaten.
+    fn player_view(&self, uid: u32) -> Option<Rect> {
+        let (mut x, mut y, mut mass) = (0.0, 0.0, 0.0);
+        for cell in self.cells.iter().filter(|cell| cell.owner == uid) {
+            x += cell.x * cell.mass;
+            y += cell.y * cell.mass;
+            mass += cell.mass;
+        }
+        if mass == 0.0 {
+            return None;
+        }
+        let (width, height) = common::view_size(mass_to_radius(mass));
+        Some(Rect::around(x / mass, y / mass, width, height))
+    }
+
+    // Splits every cell of the player that is big enough, as long as it
+    // doesn't end up with more than `MAX_CELLS`.
+    fn split(&mut self, uid: u32, dir_x: f32, dir_y: f32) {
+        let owned: Vec<_> = (0..self.cells.len())
+            .filter(|i| self.cells[*i].owner == uid)
+            .collect();
+        let mut count = owned.len();
+
+        for i in owned {
+            let cell = &self.cells[i];
+            if count == MAX_CELLS || cell.mass < MIN_SPLIT_MASS {
+                continue;
+            }
+            let (dir_x, dir_y) = cell.aim(dir_x, dir_y);
+            let mass = cell.mass / 2.0;
+            let uid = self.next_uid();
+            let half = self.cells[i].split(uid, mass, dir_x, dir_y);
+            self.cells.push(half);
+            count += 1;
+        }
+        self.index_cells();
+    }
+
+    // Breaks a cell into pieces launched all around it, as many as its
+    // player can still have.
+    fn pop(&mut self, i: usize) {
+        let owner = self.cells[i].owner;
+        let count = self.cells.iter().filter(|cell| cell.owner == owner).count();
+        let pieces = MAX_CELLS.saturating_sub(count).min(POP_PIECES);
+
+        let mass = self.cells[i].mass / (pieces + 1) as f32;
+        for n in 0..pieces {
+            let angle = n as f32 / pieces as f32 * std::f32::consts::TAU;
+            let uid = self.next_uid();
+            let piece = self.cells[i].split(uid, mass, angle.cos(), angle.sin());
+            self.cells.push(piece);
+        }
+    }
+
+    // Every cell of the player that is big enough ejects a pellet.
+    fn eject(&mut self, uid: u32, dir_x: f32, dir_y: f32) {
+        for cell in self.cells.iter_mut().filter(|cell| cell.owner == uid) {
+            if cell.mass < MIN_EJECT_MASS {
+                continue;
+            }
+            let (dir_x, dir_y) = cell.aim(dir_x, dir_y);
+            let pellet = cell.eject(dir_x, dir_y);
+
+            let id = self.next_pellet_id;
+            self.next_pellet_id = self.next_pellet_id.wrapping_add(1);
+            self.pellet_grid.insert(id, pellet.x, pellet.y);
+            self.pellets.insert(id, pellet);
+        }
+    }
+
+    // Rebuilds the cell index, every time cells move or their indices change.
+    fn index_cells(&mut self) {
+        self.cell_grid.clear();
+        self.max_radius = 0.0;
+        for (i, cell) in self.cells.iter().enumerate() {
+            self.cell_grid.insert(i, cell.x, cell.y);
+            self.max_radius = self.max_radius.max(cell.radius);
+        }
+    }
+
+    fn spawn_food(&mut self) {
+        let id = self.next_food_id;
+        self.next_food_id = self.next_food_id.wrapping_add(1);
+        let (x, y) = gen_food();
+        self.food.insert(id, (x, y));
+        self.food_grid.insert(id, x, y);
+    }
+
+    fn add_virus(&mut self, virus: Virus) {
+        let id = self.next_virus_id;
+        self.next_virus_id = self.next_virus_id.wrapping_add(1);
+        self.viruses.insert(id, virus);
+    }
+
+    // The authoritative state inside `view`, sent to clients as deltas.
+    fn snapshot(&self, view: Rect) -> Snapshot {
+        let cells = self
+            .cell_grid
+            .query(view.grow(self.max_radius))
+            .map(|(i, _, _)| &self.cells[i])
+            .filter(|cell| view.overlaps(cell.x, cell.y, cell.radius))
+            .map(|cell| {
+                let (vx, vy) = cell.body().velocity(cell.steer);
+                let state = CellState {
+                    uid: cell.uid,
+                    x: cell.x,
+                    y: cell.y,
+                    vx,
+                    vy,
+                    radius: cell.radius,
+                };
+                (cell.uid, state)
+            })
+            .collect();
+        let food = self
+            .food_grid
+            .query(view)
+            .map(|(id, x, y)| (id, (x, y)))
+            .collect();
+        let pellets = self
+            .pellet_grid
+            .query(view)
+            .map(|(id, x, y)| {
+                let pellet = &self.pellets[&id];
+                let state = PelletState {
+                    id,
+                    x,
+                    y,
+                    vx: pellet.vx,
+                    vy: pellet.vy,
+                };
+                (id, state)
+            })
+            .collect();
+        let viruses = self
+            .viruses
+            .iter()
+            .filter(|(_, virus)| view.overlaps(virus.x, virus.y, virus.radius))
+            .map(|(id, virus)| {
+                let state = VirusState {
+                    id: *id,
+                    x: virus.x,
+                    y: virus.y,
+                    radius: virus.radius,
+                };
+                (*id, state)
+            })
+            .collect();
+        Snapshot {
+            seq: 0,
+            input: 0,
+            cells,
+            food,
+            pellets,
+            viruses,
+        }
+    }
+
+    // Queues an input of the player for one of the next ticks.
+    fn queue_input(&mut self, uid: u32, seq: u32, steer: Steer) {
+        let queue = self.queued_inputs.entry(uid).or_default();
+        if queue.len() == MAX_QUEUED_INPUTS {
+            queue.pop_front();
+        }
+        queue.push_back((seq, steer));
+    }
+
+    // Steers the cells of every player with its next input. Snapshots
+    // carry the inputs applied here, so they only acknowledge inputs whose
+    // movement is in their positions.
+    fn apply_inputs(&mut self) {
+        for (uid, queue) in self.queued_inputs.iter_mut() {
+            let Some((seq, steer)) = queue.pop_front() else {
+                continue;
+            };
+            for cell in self.cells.iter_mut().filter(|cell| cell.owner == *uid) {
+                cell.steer = steer;
+            }
+            self.inputs.insert(*uid, seq);
+        }
+        self.queued_inputs.retain(|_, queue| !queue.is_empty());
+    }
+
+    // Advance the simulation by `dt` seconds and return the events to
+    // broadcast. State changes are picked up by the next snapshot.
+    fn tick(&mut self, dt: f32) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        self.apply_inputs();
+
+        // Only moving pellets change places in the index.
+        for (id, pellet) in self.pellets.iter_mut() {
+            if pellet.vx != 0.0 || pellet.vy != 0.0 {
+                self.pellet_grid.remove(*id, pellet.x, pellet.y);
+                pellet.x += pellet.vx * dt;
+                pellet.y += pellet.vy * dt;
+                clamp_to_map(&mut pellet.x, &mut pellet.y, PELLET_RADIUS);
+                decelerate(&mut pellet.vx, &mut pellet.vy, dt);
+                self.pellet_grid.insert(*id, pellet.x, pellet.y);
+            }
+        }
+
+        // Viruses eat the pellets that hit them, and shoot new viruses
+        // in the direction they were fed from.
+        let mut shot = Vec::new();
+        for virus in self.viruses.values_mut() {
+            virus.x += virus.vx * dt;
+            virus.y += virus.vy * dt;
+            clamp_to_map(&mut virus.x, &mut virus.y, virus.radius);
+            decelerate(&mut virus.vx, &mut virus.vy, dt);
+
+            let reach = Rect::around(virus.x, virus.y, 2.0 * virus.radius, 2.0 * virus.radius);
+            let fed: Vec<_> = self
+                .pellet_grid
+                .query(reach)
+                .filter(|(_, x, y)| {
+                    let (dx, dy) = (x - virus.x, y - virus.y);
+                    dx * dx + dy * dy < virus.radius * virus.radius
+                })
+                .collect();
+            for (id, x, y) in fed {
+                let pellet = self.pellets.remove(&id).unwrap();
+                self.pellet_grid.remove(id, x, y);
+
+                // Pellets that stopped push from where they are.
+                let (dir_x, dir_y) = if pellet.vx != 0.0 || pellet.vy != 0.0 {
+                    (pellet.vx, pellet.vy)
+                } else {
+                    (virus.x - x, virus.y - y)
+                };
+                let len = (dir_x * dir_x + dir_y * dir_y).sqrt().max(f32::EPSILON);
+                shot.extend(virus.feed(dir_x / len, dir_y / len));
+            }
+        }
+        for virus in shot {
+            if self.viruses.len() < MAX_VIRUSES {
+                self.add_virus(virus);
+            }
+        }
+
+        for cell in self.cells.iter_mut() {
+            // Clients predict their cells with the same step.
+            let mut body = cell.body();
+            body.step(cell.steer, dt);
+            (cell.x, cell.y) = (body.x, body.y);
+            (cell.boost_x, cell.boost_y) = (body.boost_x, body.boost_y);
+            cell.merge_timer = (cell.merge_timer - dt).max(0.0);
+
+            // Eat every food globule whose center is inside the cell.
+            let reach = Rect::around(cell.x, cell.y, 2.0 * cell.radius, 2.0 * cell.radius);
+            let eaten: Vec<_> = self
+                .food_grid
+                .query(reach)
+                .filter(|(_, x, y)| {
+                    let (dx, dy) = (x - cell.x, y - cell.y);
+                    dx * dx + dy * dy < cell.radius * cell.radius
+                })
+                .collect();
+            for (id, x, y) in &eaten {
+                self.food.remove(id);
+                self.food_grid.remove(*id, *x, *y);
+            }
+
+            // Pellets too, they are ejected outside of the cell so it
+            // only gets them back by catching up with them.
+            let pellets: Vec<_> = self
+                .pellet_grid
+                .query(reach)
+                .filter(|(_, x, y)| {
+                    let (dx, dy) = (x - cell.x, y - cell.y);
+                    dx * dx + dy * dy < cell.radius * cell.radius
+                })
+                .collect();
+            for (id, x, y) in &pellets {
+                self.pellets.remove(id);
+                self.pellet_grid.remove(*id, *x, *y);
+            }
+
+            let mass = FOOD_MASS * eaten.len() as f32 + EJECT_MASS * pellets.len() as f32;
+            if mass > 0.0 {
+                cell.mass += mass;
+                cell.radius = mass_to_radius(cell.mass);
+            }
+        }
+        // Cells big enough to eat a virus pop on it.
+        let mut popped = Vec::new();
+        for (i, cell) in self.cells.iter().enumerate() {
+            let virus = self.viruses.iter().find(|(_, virus)| {
+                let (dx, dy) = (virus.x - cell.x, virus.y - cell.y);
+                cell.mass >= virus.mass * EAT_RATIO && dx * dx + dy * dy < cell.radius * cell.radius
+            });
+            if let Some((id, _)) = virus {
+                popped.push((i, *id));
+            }
+        }
+        for (i, id) in popped {
+            // Two cells might have reached the same virus.
+            let Some(virus) = self.viruses.remove(&id) else {
+                continue;
+            };
+            let cell = &mut self.cells[i];
+            cell.mass += virus.mass;
+            cell.radius = mass_to_radius(cell.mass);
+            self.pop(i);
+        }
+
+        msgs.extend(self.merge_cells());
+        self.index_cells();
+
+        while let Some((predator, prey)) = self.find_prey() {
+            // Grow the predator first, removing the prey shifts indices.
+            let prey_mass = self.cells[prey].mass;
+            let cell = &mut self.cells[predator];
+            cell.mass += prey_mass;
+            cell.radius = mass_to_radius(cell.mass);
+
+            let prey = self.cells.swap_remove(prey);
+            self.index_cells();
+            if self.cells.iter().any(|cell| cell.owner == prey.owner) {
+                msgs.push(Message::CellRemoved(prey.uid));
+            } else {
+                msgs.push(Message::PlayerDied(prey.uid));
+                println!("Player {} was eaten", prey.owner);
+            }
+        }
+
+        // Top the food back up, a little at a time.
+        let missing = MAX_FOOD.saturating_sub(self.food.len());
+        for _ in 0..missing.min(FOOD_SPAWN_PER_TICK) {
+            self.spawn_food();
+        }
+        if self.viruses.len() < VIRUS_COUNT {
+            let (x, y) = gen_food();
+            self.add_virus(Virus::new(x, y));
+        }
+
+        let left = self.expire_away(dt);
+        msgs.extend(left.into_iter().map(Message::PlayerLeft));
+        msgs
+    }
+
+    // Cells of a player push each other apart until they can merge, then
+    // the bigger one takes in the other once it covers its center.
+    // Returns the events for the merged cells.
+    fn merge_cells(&mut self) -> Vec<Message> {
+        let mut players: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, cell) in self.cells.iter().enumerate() {
+            players.entry(cell.owner).or_default().push(i);
+        }
+
+        let mut merged = Vec::new();
+        for owned in players.values().filter(|owned| owned.len() > 1) {
+            for (n, &i) in owned.iter().enumerate() {
+                for &j in &owned[n + 1..] {
+                    if merged.contains(&i) || merged.contains(&j) {
+                        continue;
+                    }
+                    let (a, b) = (&self.cells[i], &self.cells[j]);
+                    let (dx, dy) = (b.x - a.x, b.y - a.y);
+                    let distance = (dx * dx + dy * dy).sqrt();
+
+                    if a.merge_timer > 0.0 || b.merge_timer > 0.0 {
+                        let overlap = a.radius + b.radius - distance;
+                        if overlap > 0.0 && distance > 0.0 {
+                            let (push_x, push_y) =
+                                (dx / distance * overlap / 2.0, dy / distance * overlap / 2.0);
+                            for (k, sign) in [(i, -1.0), (j, 1.0)] {
+                                let cell = &mut self.cells[k];
+                                cell.x += sign * push_x;
+                                cell.y += sign * push_y;
+                                clamp_to_map(&mut cell.x, &mut cell.y, cell.radius);
+                            }
+                        }
+                    } else if distance < a.radius.max(b.radius) {
+                        let (big, small) = if a.mass >= b.mass { (i, j) } else { (j, i) };
+                        self.cells[big].mass += self.cells[small].mass;
+                        self.cells[big].radius = mass_to_radius(self.cells[big].mass);
+                        merged.push(small);
+                    }
+                }
+            }
+        }
+
+        // Remove from the back, so that the other indices stay valid.
+        merged.sort_unstable();
+        merged
+            .into_iter()
+            .rev()
+            .map(|i| Message::CellRemoved(self.cells.swap_remove(i).uid))
+            .collect()
+    }
+
+    // Returns the indices of a (predator, prey) pair, if any.
+    fn find_prey(&self) -> Option<(usize, usize)> {
+        for (i, a) in self.cells.iter().enumerate() {
+            // The prey's center has to be inside the predator.
+            let reach = Rect::around(a.x, a.y, 2.0 * a.radius, 2.0 * a.radius);
+            for (j, _, _) in self.cell_grid.query(reach) {
+                let b = &self.cells[j];
+                if a.owner != b.owner && a.can_eat(b) {
+                    return Some((i, j));
+                }
+            }
+        }
+        None
+    }
+}
+
+// How many unacknowledged snapshots are kept per client.
+const MAX_PENDING_SNAPSHOTS: usize = 2 * common::TICK_RATE as usize;
+
+// Tracks the snapshots sent to a client, every update is encoded as a
+// delta against the latest one it acknowledged. Only what is inside the
+// player's view is sent.
+struct ClientView {
+    uid: u32,
+    rect: Rect,
+    acked: Snapshot,
+    pending: VecDeque<Snapshot>,
+}
+
+impl ClientView {
+    fn new(uid: u32, rect: Rect, baseline: Snapshot) -> Self {
+        Self {
+            uid,
+            rect,
+            acked: baseline,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // The last snapshot sent to the client.
+    fn latest(&self) -> &Snapshot {
+        self.pending.back().unwrap_or(&self.acked)
+    }
+
+    // Whether the client knows about the cell.
+    fn sees(&self, uid: u32) -> bool {
+        uid == self.uid || self.latest().cells.contains_key(&uid)
+    }
+
+    // Returns the cells that entered and left the view since the last
+    // update, followed by the delta.
+    fn update(&mut self, game: &Game) -> Vec<Message> {
+        // Dead players keep looking at where they were eaten.
+        if let Some(rect) = game.player_view(self.uid) {
+            self.rect = rect;
+        }
+        let mut snapshot = game.snapshot(self.rect);
+
+        let last = self.latest();
+        let mut msgs = Vec::new();
+        for uid in snapshot.cells.keys() {
+            if !last.cells.contains_key(uid) {
+                msgs.push(Message::EnterView(game.cell(*uid).unwrap().info()));
+            }
+        }
+        for uid in last.cells.keys() {
+            // Cells gone from the game were announced with their own event.
+            if !snapshot.cells.contains_key(uid) && game.cell(*uid).is_some() {
+                msgs.push(Message::LeaveView(*uid));
+            }
+        }
+
+        snapshot.seq = last.seq.wrapping_add(1);
+        snapshot.input = game.inputs.get(&self.uid).copied().unwrap_or_default();
+        let delta = self.acked.diff(&snapshot);
+
+        // A client that stops acknowledging just gets bigger deltas.
+        if self.pending.len() == MAX_PENDING_SNAPSHOTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(snapshot);
+        msgs.push(Message::Delta(delta));
+        msgs
+    }
+
+    fn ack(&mut self, seq: u32) {
+        if let Some(i) = self.pending.iter().position(|snapshot| snapshot.seq == seq) {
+            // Older snapshots will never be used as a base again.
+            self.pending.drain(..i);
+            self.acked = self.pending.pop_front().unwrap();
         }
     }
 }
@@ -75,81 +999,186 @@ async fn handle_client(
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut ws = fut.await?;
 
+    let (name, token) = match handshake(&mut ws).await? {
+        Some(hello) => hello,
+        None => return Ok(()),
+    };
+
     let tx = game.borrow().incoming.clone();
-    let mut outgoing = game.borrow().broadcast.subscribe();
-    
-    // Spawn food.
-    let frames = game
-        .borrow()
-        .food
-        .iter()
-        .map(|(x, y)| {
-            let msg = Message::SpawnFood(*x, *y);
-            Frame::new(true, OpCode::Binary, None, msg_to_frame(msg).into())
-        })
-        .collect::<Vec<_>>();
-
-    for frame in frames {
-        ws.write_frame(frame).await?;
-    }
-
-    // Spawn cells.
-    let frames = game
-        .borrow()
-        .cells
-        .iter()
-        .map(|cell| {
-            let msg = Message::NewPlayer(cell.x, cell.y, cell.uid);
-            Frame::new(true, OpCode::Binary, None, msg_to_frame(msg).into())
-        })
-        .collect::<Vec<_>>();
-
-    for frame in frames {
-        ws.write_frame(frame).await?;
-    }
-
-    let uid = {
-      let mut game = game.borrow_mut();
-      let uid = game.cells.len() as u32;
-      game.cells.push(Player {
-          x: 0.0,
-          y: 0.0,
-          radius: 10.0,
-          uid,
-      });
-      uid
+
+    // Subscribe in the same borrow the world is copied in, so that no
+    // update is missed or applied twice by the client.
+    let (uid, closed, mut outgoing, joined, mut view) = {
+        let mut game = game.borrow_mut();
+        // Players that come back in time keep their cells.
+        let uid = match game.resume(token) {
+            Some(uid) => {
+                println!("Player {} resumed", uid);
+                uid
+            }
+            None => {
+                let uid = game.add_player(name);
+                println!("Spawned player with uid {}", uid);
+                uid
+            }
+        };
+        let token = game.issue_token(uid);
+        let closed = game.connect(uid);
+        let outgoing = game.broadcast.subscribe();
+
+        let rect = game.player_view(uid).unwrap();
+        // The same queries as `Game::snapshot`, so that the first delta
+        // agrees with the world about what is in view.
+        let food: Vec<_> = game
+            .food_grid
+            .query(rect)
+            .map(|(id, x, y)| FoodInfo { id, x, y })
+            .collect();
+        // Everyone but the player, its own cells enter the view with the
+        // first update, after `Start` told the client who it is.
+        let cells: Vec<_> = game
+            .cell_grid
+            .query(rect.grow(game.max_radius))
+            .map(|(i, _, _)| &game.cells[i])
+            .filter(|cell| cell.owner != uid && rect.overlaps(cell.x, cell.y, cell.radius))
+            .map(Player::info)
+            .collect();
+        // What the client knows after applying the world snapshot.
+        let baseline = Snapshot::from_world(&food, &cells);
+        let world = Message::world_snapshot((MAP_WIDTH, MAP_HEIGHT), food, cells);
+        let joined = [world, Message::Start(uid, token)];
+
+        (uid, closed, outgoing, joined, ClientView::new(uid, rect, baseline))
     };
 
-    // Spawn the player.
-    let msg = Message::Start(0.0, 0.0, uid);
-    tx.send(Message::NewPlayer(0.0, 0.0, uid)).unwrap();
+    let result = tokio::select! {
+        result = play(&mut ws, uid, &game, &joined, &mut view, &tx, &mut outgoing) => result,
+        // Another connection resumed the player, this one is stale.
+        _ = closed.notified() => {
+            println!("Player {} moved to another connection", uid);
+            let _ = ws.write_frame(Frame::close(1000, b"resumed on another connection")).await;
+            Ok(())
+        }
+    };
+
+    // Whatever else ended the connection, the game loop keeps the player's
+    // cells around for a while in case it comes back.
+    if game.borrow_mut().disconnect(uid, &closed) {
+        tx.send(Message::PlayerLeft(uid))?;
+        println!("Player {} left", uid);
+    }
+    result
+}
+
+// Returns the name and resume token of the client's `Hello`, or why the
+// client is turned away.
+fn read_hello(payload: &[u8]) -> Result<(String, u64), String> {
+    let not_hello = || "expected a hello message".to_string();
+    let (_, header) = HelloHeader::from_bytes((payload, 0)).map_err(|_| not_hello())?;
+    if header.id != HELLO_ID {
+        return Err(not_hello());
+    }
+    if header.protocol_version != PROTOCOL_VERSION {
+        return Err(format!(
+            "incompatible protocol version {}, server speaks version {}",
+            header.protocol_version, PROTOCOL_VERSION
+        ));
+    }
+
+    match Message::try_from(payload) {
+        Ok(Message::Hello {
+            client_name,
+            resume_token,
+            ..
+        }) => Ok((client_name.to_string_lossy().into_owned(), resume_token)),
+        Ok(_) => Err(not_hello()),
+        Err(e) => Err(format!("invalid hello message: {}", e)),
+    }
+}
+
+// Waits for the client's `Hello` and returns its name and resume token.
+// Clients speaking another protocol version are sent away with a close
+// frame.
+async fn handshake(
+    ws: &mut WebSocket<Upgraded>,
+) -> Result<Option<(String, u64)>, Box<dyn std::error::Error + Send + Sync>> {
+    let frame = ws.read_frame().await?;
+    if frame.opcode != OpCode::Binary {
+        return Ok(None);
+    }
+
+    match read_hello(frame.payload.as_ref()) {
+        Ok(hello) => Ok(Some(hello)),
+        Err(reason) => {
+            ws.write_frame(Frame::close(1002, reason.as_bytes())).await?;
+            Ok(None)
+        }
+    }
+}
+
+// Runs a joined player until the connection is closed.
+async fn play(
+    ws: &mut WebSocket<Upgraded>,
+    uid: u32,
+    game: &Rc<RefCell<Game>>,
+    joined: &[Message],
+    view: &mut ClientView,
+    tx: &mpsc::UnboundedSender<Message>,
+    outgoing: &mut broadcast::Receiver<Vec<Message>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let msg = Message::Welcome {
+        uid,
+        server_version: PROTOCOL_VERSION,
+        map_size: (MAP_WIDTH, MAP_HEIGHT),
+    };
     let frame = Frame::new(true, OpCode::Binary, None, msg_to_frame(msg).into());
     ws.write_frame(frame).await?;
-    println!("Spawned player with uid {}", uid);
-    
+
+    // The world and the player's `Start` go in a single frame.
+    ws.write_frame(batch_frame(joined)).await?;
+
     loop {
         tokio::select! {
             frame = ws.read_frame() => {
-              let frame = frame?;
-              match frame.opcode {
-                OpCode::Binary => {
-                let msg = common::Message::try_from(frame.payload.as_ref())?;
-                tx.send(msg)?;
-            },
-            _ => {},
-              }            }
-            msg = outgoing.recv() => {
-                let msg = msg?;
-                if msg.uid() == Some(uid) {
-                    continue;
+                let frame = frame?;
+                match frame.opcode {
+                    OpCode::Binary => {
+                        for msg in common::decode_batch(frame.payload.as_ref())? {
+                            match msg {
+                                // Only inputs are accepted from clients, and always
+                                // on behalf of this connection's player.
+                                Message::MovePlayer(x, y, _, seq) => {
+                                    tx.send(Message::MovePlayer(x, y, uid, seq))?;
+                                }
+                                Message::MoveTo(x, y, _, seq) => {
+                                    tx.send(Message::MoveTo(x, y, uid, seq))?;
+                                }
+                                Message::Split(_, x, y) => {
+                                    tx.send(Message::Split(uid, x, y))?;
+                                }
+                                Message::Eject(_, x, y) => {
+                                    tx.send(Message::Eject(uid, x, y))?;
+                                }
+                                Message::Ack(seq) => view.ack(seq),
+                                _ => {}
+                            }
+                        }
+                    }
+                    OpCode::Close => return Ok(()),
+                    _ => {}
                 }
-                let frame = Frame::new(true, OpCode::Binary, None, msg_to_frame(msg).into());
-                ws.write_frame(frame).await?;
+            }
+            msgs = outgoing.recv() => {
+                let mut msgs = msgs?;
+                // Cells are spawned when they come into view, and events
+                // about cells out of view are of no interest.
+                msgs.retain(|msg| msg.uid().is_none_or(|uid| view.sees(uid)));
+                // Events first, then the state after this tick.
+                msgs.extend(view.update(&game.borrow()));
+                ws.write_frame(batch_frame(&msgs)).await?;
             }
         }
     }
-
-    Ok(())
 }
 
 async fn server_upgrade(
@@ -169,37 +1198,83 @@ async fn server_upgrade(
 
 async fn game_loop(
     game: Rc<RefCell<Game>>,
-    mut incoming_rx: broadcast::Receiver<Message>,
-    mut outgoing_tx: broadcast::Sender<Message>,
+    mut incoming_rx: mpsc::UnboundedReceiver<Message>,
+    outgoing_tx: broadcast::Sender<Vec<Message>>,
 ) {
-    // Players send its events, here we actually handle them.
+    let dt = 1.0 / common::TICK_RATE as f32;
+    let mut ticker = tokio::time::interval(Duration::from_secs_f32(dt));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    // Messages waiting to be broadcast with the next tick.
+    let mut pending = Vec::new();
+
     loop {
-        let msg = incoming_rx.recv().await.unwrap();
-        match msg {
-            Message::NewPlayer(x, y, uid) => {
-                // Broadcast new player to all players.
-                let msg = Message::NewPlayer(x, y, uid);
-                outgoing_tx.send(msg).unwrap();
+        tokio::select! {
+            // Players send its events, here we actually handle them.
+            // The game holds a sender, the channel never closes.
+            Some(msg) = incoming_rx.recv() => {
+                match msg {
+                    // Its cells leave the game once the player stayed away
+                    // for too long.
+                    Message::PlayerLeft(uid) => game.borrow_mut().leave(uid),
+                    // Inputs are applied by the next ticks, one per tick.
+                    Message::MovePlayer(x, y, uid, seq) => {
+                        game.borrow_mut().queue_input(uid, seq, Steer::Direction(x, y));
+                    }
+                    Message::MoveTo(x, y, uid, seq) => {
+                        game.borrow_mut().queue_input(uid, seq, Steer::Target(x, y));
+                    }
+                    Message::Split(uid, x, y) => game.borrow_mut().split(uid, x, y),
+                    Message::Eject(uid, x, y) => game.borrow_mut().eject(uid, x, y),
+                    _ => {}
+                }
             }
-            Message::MovePlayer(x, y, uid) => {
-                // Broadcast move player to all players.
-                let msg = Message::MovePlayer(x, y, uid);
-                outgoing_tx.send(msg).unwrap();
+            _ = ticker.tick() => {
+                pending.extend(game.borrow_mut().tick(dt));
+                // Nobody might be listening yet.
+                let _ = outgoing_tx.send(std::mem::take(&mut pending));
             }
-            _ => {}
         }
     }
 }
 
 const BROADCAST_BUFFER_SIZE: usize = 128;
 
+// Accepts connections and runs a player for each of them, on the local
+// set the game runs on.
+async fn serve(
+    listener: TcpListener,
+    game: Rc<RefCell<Game>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        println!("Client connected");
+        let game = game.clone();
+        tokio::task::spawn_local(async move {
+            let conn_fut = Http::new()
+                .with_executor(SpawnExecutor)
+                .serve_connection(
+                    stream,
+                    service_fn(move |req| {
+                        let game = game.clone();
+                        async move { server_upgrade(req, game).await }
+                    }),
+                )
+                .with_upgrades();
+            if let Err(e) = conn_fut.await {
+                println!("An error occurred: {:?}", e);
+            }
+        });
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    println!("Server started, listening on {}", "127.0.0.1:8080");
+    println!("Server started, listening on 127.0.0.1:8080");
 
     // Initialize the game state.
-    let (incoming_tx, incoming_rx) = channel(BROADCAST_BUFFER_SIZE);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
     let (outgoing_tx, _) = channel(BROADCAST_BUFFER_SIZE);
     let game = Rc::new(RefCell::new(Game::new(incoming_tx, outgoing_tx.clone())));
 
@@ -208,28 +1283,642 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     localset.spawn_local(game_loop(game.clone(), incoming_rx, outgoing_tx));
 
     // Spawn a task that will listen for incoming connections.
-    localset
-        .run_until(async move {
-            loop {
-                let (stream, _) = listener.accept().await?;
-                println!("Client connected");
-                let game = game.clone();
-                tokio::task::spawn_local(async move {
-                    let conn_fut = Http::new()
-                        .with_executor(SpawnExecutor)
-                        .serve_connection(
-                            stream,
-                            service_fn(move |req| {
-                                let game = game.clone();
-                                async move { server_upgrade(req, game).await }
-                            }),
-                        )
-                        .with_upgrades();
-                    if let Err(e) = conn_fut.await {
-                        println!("An error occurred: {:?}", e);
+    localset.run_until(serve(listener, game)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CAMERA_HEIGHT, CAMERA_WIDTH};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use tokio::net::TcpStream;
+    use tokio::task::LocalSet;
+    use tokio::time::timeout;
+
+    fn new_game() -> Game {
+        let (incoming, _) = mpsc::unbounded_channel();
+        let (broadcast, _) = channel(BROADCAST_BUFFER_SIZE);
+        Game::new(incoming, broadcast)
+    }
+
+    #[test]
+    fn uids_are_unique_across_disconnects() {
+        let mut game = new_game();
+        let mut connected = Vec::new();
+        let mut seen = HashSet::new();
+
+        for i in 0..1000 {
+            let uid = game.add_player(String::new());
+            assert!(seen.insert(uid), "uid {} was handed out twice", uid);
+            connected.push(uid);
+
+            // Disconnect some of the players, oldest first.
+            if i % 3 == 0 {
+                let uid = connected.remove(0);
+                assert_eq!(game.remove_player(uid), vec![uid]);
+            }
+        }
+        assert_eq!(game.cells.len(), connected.len());
+    }
+
+    // Runs a game and serves it on a free port like `main` does, has to be
+    // called on a `LocalSet`.
+    async fn start_server() -> (Rc<RefCell<Game>>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (outgoing_tx, _) = channel(BROADCAST_BUFFER_SIZE);
+        let game = Rc::new(RefCell::new(Game::new(incoming_tx, outgoing_tx.clone())));
+        tokio::task::spawn_local(game_loop(game.clone(), incoming_rx, outgoing_tx));
+        tokio::task::spawn_local(serve(listener, game.clone()));
+        (game, addr)
+    }
+
+    // Opens a websocket to the server like a client.
+    async fn connect(addr: SocketAddr) -> WebSocket<Upgraded> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let req = Request::builder()
+            .uri(format!("http://{}/", addr))
+            .header("Host", addr.to_string())
+            .header(hyper::header::UPGRADE, "websocket")
+            .header(hyper::header::CONNECTION, "upgrade")
+            .header("Sec-WebSocket-Key", fastwebsockets::handshake::generate_key())
+            .header("Sec-WebSocket-Version", "13")
+            .body(Body::empty())
+            .unwrap();
+        let (ws, _) = fastwebsockets::handshake::client(&SpawnExecutor, req, stream)
+            .await
+            .unwrap();
+        ws
+    }
+
+    // Connects and joins the game like a client, returns the connection
+    // and the uid and resume token from the `Start`.
+    async fn join(addr: SocketAddr, resume_token: u64) -> (WebSocket<Upgraded>, u32, u64) {
+        let mut ws = connect(addr).await;
+        let hello = Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: CString::new("test").unwrap(),
+            resume_token,
+        };
+        ws.write_frame(batch_frame(&[hello])).await.unwrap();
+        loop {
+            let frame = ws.read_frame().await.unwrap();
+            for msg in common::decode_batch(frame.payload.as_ref()).unwrap() {
+                if let Message::Start(uid, token) = msg {
+                    return (ws, uid, token);
+                }
+            }
+        }
+    }
+
+    // Waits for the connections and the game loop to catch up.
+    async fn eventually(mut done: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn players_leave_when_their_connections_close() {
+        LocalSet::new()
+            .run_until(async {
+                let (game, addr) = start_server().await;
+                let mut connected = Vec::new();
+                let mut closed = Vec::new();
+                let mut seen = HashSet::new();
+
+                for i in 0..30 {
+                    let (ws, uid, _) = join(addr, 0).await;
+                    assert!(seen.insert(uid), "uid {} was handed out twice", uid);
+                    connected.push((ws, uid));
+
+                    // Close some of the connections, oldest first.
+                    if i % 3 == 0 {
+                        let (_, uid) = connected.remove(0);
+                        closed.push(uid);
                     }
-                });
+                }
+
+                // Closed connections reach the game loop through its channel.
+                eventually(|| closed.iter().all(|uid| game.borrow().away.contains_key(uid))).await;
+                assert!(connected.iter().all(|(_, uid)| !game.borrow().away.contains_key(uid)));
+
+                let mut game = game.borrow_mut();
+                game.tick(RESUME_GRACE);
+                assert!(game.cells.iter().all(|cell| !closed.contains(&cell.owner)));
+                assert_eq!(game.cells.len(), connected.len());
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn players_resume_on_a_new_connection() {
+        LocalSet::new()
+            .run_until(async {
+                let (game, addr) = start_server().await;
+                let (mut old, uid, token) = join(addr, 0).await;
+                let (_new, resumed, _) = join(addr, token).await;
+                assert_eq!(resumed, uid);
+
+                // The server closes the old connection, which does not send
+                // the player away.
+                timeout(Duration::from_secs(1), async {
+                    while old.read_frame().await.unwrap().opcode != OpCode::Close {}
+                })
+                .await
+                .unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(game.borrow().away.is_empty());
+                assert_eq!(game.borrow().cells.len(), 1);
+            })
+            .await
+    }
+
+    #[test]
+    fn uids_skip_connected_players_on_wrap_around() {
+        let mut game = new_game();
+        let first = game.add_player(String::new());
+        game.next_uid = u32::MAX;
+
+        assert_eq!(game.add_player(String::new()), u32::MAX);
+        let uid = game.add_player(String::new());
+        assert_ne!(uid, first);
+        assert_eq!(game.cells.iter().filter(|cell| cell.uid == uid).count(), 1);
+
+        // Eaten, but still connected and watching.
+        let dead = game.add_player(String::new());
+        game.connect(dead);
+        game.cells.retain(|cell| cell.owner != dead);
+        game.next_uid = dead;
+        assert_ne!(game.add_player(String::new()), dead);
+    }
+
+    #[test]
+    fn players_resume_with_their_token() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        game.cells[0].mass = 4.0 * START_MASS;
+        game.split(uid, 1.0, 0.0);
+        let token = game.issue_token(uid);
+
+        let cells = |game: &Game| {
+            game.cells
+                .iter()
+                .map(|cell| (cell.uid, cell.x, cell.mass))
+                .collect::<Vec<_>>()
+        };
+        let before = cells(&game);
+        game.leave(uid);
+        assert_eq!(game.resume(0), None);
+        assert_eq!(game.resume(token), Some(uid));
+        assert_eq!(cells(&game), before);
+
+        // Tokens are good for one resume.
+        let next = game.issue_token(uid);
+        game.leave(uid);
+        assert_eq!(game.resume(token), None);
+        assert_eq!(game.resume(next), Some(uid));
+    }
+
+    #[test]
+    fn hellos_are_checked_for_the_version_first() {
+        let hello = Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: CString::new("bob").unwrap(),
+            resume_token: 5,
+        };
+        let bytes = hello.to_bytes().unwrap();
+        assert_eq!(read_hello(&bytes), Ok(("bob".to_string(), 5)));
+
+        // Version 12 had no resume token yet.
+        let old = [&[HELLO_ID, 12, 0][..], b"bob\0"].concat();
+        let reason = read_hello(&old).unwrap_err();
+        assert!(reason.starts_with("incompatible protocol version 12"), "{}", reason);
+
+        assert!(read_hello(&bytes[..bytes.len() - 1]).unwrap_err().starts_with("invalid"));
+        let ack = Message::Ack(1).to_bytes().unwrap();
+        assert_eq!(read_hello(&ack), Err("expected a hello message".to_string()));
+        assert!(read_hello(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn old_clients_are_sent_away_with_the_reason() {
+        LocalSet::new()
+            .run_until(async {
+                let (_, addr) = start_server().await;
+                let mut ws = connect(addr).await;
+                let old = [&[HELLO_ID, 12, 0][..], b"bob\0"].concat();
+                ws.write_frame(Frame::binary(old.into())).await.unwrap();
+
+                let frame = ws.read_frame().await.unwrap();
+                assert_eq!(frame.opcode, OpCode::Close);
+                assert_eq!(&frame.payload[..2], 1002u16.to_be_bytes());
+                let reason = String::from_utf8_lossy(&frame.payload[2..]);
+                assert!(reason.starts_with("incompatible protocol version 12"), "{}", reason);
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn resuming_takes_over_the_old_connection() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        let token = game.issue_token(uid);
+        let old = game.connect(uid);
+        game.queue_input(uid, 7, Steer::Direction(1.0, 0.0));
+        game.tick(0.0);
+        game.queue_input(uid, 8, Steer::Direction(1.0, 0.0));
+
+        // The old connection did not notice that it dropped yet.
+        assert_eq!(game.resume(token), Some(uid));
+        let token = game.issue_token(uid);
+        let new = game.connect(uid);
+        timeout(Duration::from_secs(1), old.notified()).await.unwrap();
+        // Inputs of the new connection are numbered from the start.
+        game.tick(0.0);
+        assert_eq!(game.inputs.get(&uid), None);
+        assert!(!game.disconnect(uid, &old));
+        game.leave(uid);
+        assert!(game.away.is_empty());
+
+        // The client was faster than the game loop with its `PlayerLeft`.
+        assert!(game.disconnect(uid, &new));
+        assert_eq!(game.resume(token), Some(uid));
+        game.issue_token(uid);
+        let new = game.connect(uid);
+        game.leave(uid);
+        assert!(game.away.is_empty());
+
+        assert!(game.disconnect(uid, &new));
+        game.leave(uid);
+        assert!(game.away.contains_key(&uid));
+    }
+
+    #[test]
+    fn away_players_leave_after_the_grace_period() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        let token = game.issue_token(uid);
+        game.leave(uid);
+
+        assert!(!game.tick(RESUME_GRACE / 2.0).contains(&Message::PlayerLeft(uid)));
+        assert!(game.cell(uid).is_some());
+        assert!(game.tick(RESUME_GRACE / 2.0).contains(&Message::PlayerLeft(uid)));
+        assert!(game.cell(uid).is_none());
+        assert_eq!(game.resume(token), None);
+
+        // Eaten players have nothing to come back to.
+        let uid = game.add_player(String::new());
+        game.issue_token(uid);
+        game.cells.retain(|cell| cell.owner != uid);
+        game.leave(uid);
+        assert!(game.tokens.is_empty());
+        assert!(game.away.is_empty());
+    }
+
+    #[test]
+    fn cells_enter_and_leave_the_view() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        let other = game.add_player(String::new());
+        game.cells[1].x = CAMERA_WIDTH;
+        game.index_cells();
+
+        let rect = game.player_view(uid).unwrap();
+        let mut view = ClientView::new(uid, rect, Snapshot::from_world(&[], &[]));
+        let msgs = view.update(&game);
+        // The player's own cell too, the client only knows its uid.
+        assert!(matches!(&msgs[0], Message::EnterView(info) if info.uid == uid));
+        assert!(!msgs.iter().any(|msg| msg.uid() == Some(other)));
+        assert!(!view.sees(other));
+
+        game.cells[1].x = CAMERA_WIDTH / 4.0;
+        game.index_cells();
+        let msgs = view.update(&game);
+        assert!(matches!(&msgs[0], Message::EnterView(info) if info.uid == other));
+        assert!(view.sees(other));
+
+        game.cells[1].x = -CAMERA_WIDTH;
+        game.index_cells();
+        let msgs = view.update(&game);
+        assert_eq!(msgs[0], Message::LeaveView(other));
+        assert!(!view.sees(other));
+    }
+
+    #[test]
+    fn deltas_carry_the_last_applied_input() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        let rect = game.player_view(uid).unwrap();
+        let mut view = ClientView::new(uid, rect, Snapshot::from_world(&[], &[]));
+
+        game.inputs.insert(uid, 42);
+        let msgs = view.update(&game);
+        assert!(matches!(msgs.last(), Some(Message::Delta(delta)) if delta.input == 42));
+    }
+
+    #[test]
+    fn deltas_only_ack_inputs_a_tick_applied() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        let rect = game.player_view(uid).unwrap();
+        let mut view = ClientView::new(uid, rect, Snapshot::from_world(&[], &[]));
+        let acked = |msgs: &[Message]| match msgs.last() {
+            Some(Message::Delta(delta)) => delta.input,
+            msg => panic!("expected a delta, got {:?}", msg),
+        };
+
+        // Both arrive within a tick, each gets a tick of its own.
+        game.queue_input(uid, 1, Steer::Direction(1.0, 0.0));
+        game.queue_input(uid, 2, Steer::Direction(0.0, 1.0));
+        let dt = 1.0 / common::TICK_RATE as f32;
+        game.tick(dt);
+        assert_eq!(game.cells[0].steer, Steer::Direction(1.0, 0.0));
+
+        // Arrives after the tick, but before the client's snapshot is taken.
+        game.queue_input(uid, 3, Steer::Direction(-1.0, 0.0));
+        assert_eq!(acked(&view.update(&game)), 1);
+
+        game.tick(dt);
+        assert_eq!(acked(&view.update(&game)), 2);
+        game.tick(dt);
+        assert_eq!(acked(&view.update(&game)), 3);
+        assert_eq!(game.cells[0].steer, Steer::Direction(-1.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn bursts_of_inputs_reach_the_game_loop() {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (outgoing_tx, _) = channel(BROADCAST_BUFFER_SIZE);
+        let game = Rc::new(RefCell::new(Game::new(incoming_tx, outgoing_tx.clone())));
+        let uid = game.borrow_mut().add_player(String::new());
+
+        // Far more than a broadcast channel would hold between ticks.
+        let tx = game.borrow().incoming.clone();
+        let last = 10 * BROADCAST_BUFFER_SIZE as u32;
+        for seq in 1..=last {
+            tx.send(Message::MovePlayer(1.0, 0.0, uid, seq)).unwrap();
+        }
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                tokio::task::spawn_local(game_loop(game.clone(), incoming_rx, outgoing_tx));
+                while game.borrow().inputs.get(&uid) != Some(&last) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+    }
+
+    #[test]
+    fn bigger_cells_eat_other_players() {
+        let mut game = new_game();
+        game.food.clear();
+        game.food_grid.clear();
+        for virus in game.viruses.values_mut() {
+            (virus.x, virus.y) = (MAP_WIDTH / 2.0, MAP_HEIGHT / 2.0);
+        }
+        // Both start at the same place.
+        let big = game.add_player(String::new());
+        let small = game.add_player(String::new());
+        game.tick(0.0);
+        assert_eq!(game.cells.len(), 2, "too close in size");
+
+        let mass = START_MASS * EAT_RATIO;
+        game.cells[0].mass = mass;
+        game.cells[0].radius = mass_to_radius(mass);
+        game.food.clear();
+        game.food_grid.clear();
+        let msgs = game.tick(0.0);
+        assert!(msgs.contains(&Message::PlayerDied(small)));
+        assert_eq!(game.cells.len(), 1);
+        assert_eq!(game.cells[0].owner, big);
+        assert_eq!(game.cells[0].mass, mass + START_MASS);
+
+        // Its own cells don't eat each other, they merge after the cooldown.
+        let uid = game.next_uid();
+        let mut piece = game.cells[0].split(uid, START_MASS, 1.0, 0.0);
+        (piece.x, piece.y) = (game.cells[0].x, game.cells[0].y);
+        game.cells.push(piece);
+        game.index_cells();
+        game.tick(0.0);
+        assert_eq!(game.cells.len(), 2);
+        assert_eq!(game.cells[0].mass, mass);
+    }
+
+    #[test]
+    fn splitting_launches_half_of_the_mass() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        game.split(uid, 1.0, 0.0);
+        assert_eq!(game.cells.len(), 1, "too small to split");
+
+        game.cells[0].mass = 4.0 * START_MASS;
+        game.split(uid, 3.0, 0.0);
+        game.split(uid, 0.0, 1.0);
+        assert_eq!(game.cells.len(), 4);
+        assert!(game.cells.iter().all(|cell| cell.owner == uid && cell.mass == START_MASS));
+        assert_eq!((game.cells[1].boost_x, game.cells[1].boost_y), (SPLIT_SPEED, 0.0));
+        assert!(game.cells[1].x > game.cells[0].x);
+    }
+
+    #[test]
+    fn split_cells_merge_after_the_cooldown() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        game.cells[0].mass = 2.0 * START_MASS;
+        game.split(uid, 1.0, 0.0);
+        let uids = [game.cells[0].uid, game.cells[1].uid];
+
+        // Heading back into each other, they stay apart for now.
+        game.cells[1].steer = Steer::Direction(-1.0, 0.0);
+        let dt = 1.0 / common::TICK_RATE as f32;
+        for _ in 0..(MERGE_COOLDOWN / dt) as usize - 1 {
+            assert!(game.tick(dt).is_empty());
+        }
+        assert_eq!(game.cells.len(), 2);
+
+        let msgs: Vec<_> = (0..10).flat_map(|_| game.tick(dt)).collect();
+        // Whichever ate more food on the way takes in the other.
+        assert_eq!(game.cells.len(), 1);
+        let merged = uids.iter().find(|uid| **uid != game.cells[0].uid).unwrap();
+        assert_eq!(msgs, vec![Message::CellRemoved(*merged)]);
+        assert!(game.cells[0].mass >= 2.0 * START_MASS);
+    }
+
+    #[test]
+    fn ejected_pellets_slow_down_and_can_be_eaten() {
+        let mut game = new_game();
+        game.food.clear();
+        game.food_grid.clear();
+        // Out of the pellet's way.
+        for virus in game.viruses.values_mut() {
+            (virus.x, virus.y) = (MAP_WIDTH / 2.0, MAP_HEIGHT / 2.0);
+        }
+        let uid = game.add_player(String::new());
+        game.eject(uid, 1.0, 0.0);
+        assert!(game.pellets.is_empty(), "too small to eject");
+
+        game.cells[0].mass = START_MASS + EJECT_MASS;
+        game.eject(uid, 1.0, 0.0);
+        assert_eq!(game.cells[0].mass, START_MASS);
+        assert_eq!(game.pellets.len(), 1);
+
+        let dt = 1.0 / common::TICK_RATE as f32;
+        for _ in 0..common::TICK_RATE {
+            game.tick(dt);
+        }
+        let pellet = game.pellets.values().next().unwrap();
+        assert_eq!((pellet.vx, pellet.vy), (0.0, 0.0));
+        assert!(pellet.x > game.cells[0].radius + PELLET_RADIUS);
+
+        // Another player picks it up.
+        let (x, y) = (pellet.x, pellet.y);
+        game.add_player(String::new());
+        (game.cells[1].x, game.cells[1].y) = (x, y);
+        game.tick(dt);
+        assert!(game.pellets.is_empty());
+        assert!(game.cells[1].mass >= START_MASS + EJECT_MASS);
+    }
+
+    #[test]
+    fn cells_slow_down_and_stop_at_their_target() {
+        let mut game = new_game();
+        game.add_player(String::new());
+        game.cells[0].steer = Steer::Target(200.0, 0.0);
+
+        let dt = 1.0 / common::TICK_RATE as f32;
+        let mut last = game.cells[0].x;
+        for _ in 0..(3 * common::TICK_RATE) {
+            game.tick(dt);
+            let cell = &game.cells[0];
+            assert!(cell.x >= last && cell.x <= 200.0, "{} after {}", cell.x, last);
+            last = cell.x;
+        }
+        assert!((200.0 - game.cells[0].x) < 1.0, "{}", game.cells[0].x);
+    }
+
+    #[test]
+    fn cells_stay_inside_the_map() {
+        let mut game = new_game();
+        game.add_player(String::new());
+        game.cells[0].steer = Steer::Direction(1.0, -1.0);
+
+        let dt = 1.0 / common::TICK_RATE as f32;
+        for _ in 0..(MAP_WIDTH / common::PLAYER_SPEED / dt) as usize {
+            game.tick(dt);
+        }
+        let cell = &game.cells[0];
+        assert_eq!(cell.x, MAP_WIDTH / 2.0 - cell.radius);
+        assert_eq!(cell.y, -MAP_HEIGHT / 2.0 + cell.radius);
+    }
+
+    #[test]
+    fn big_cells_pop_on_viruses() {
+        let mut game = new_game();
+        let uid = game.add_player(String::new());
+        let (x, y) = {
+            let virus = game.viruses.values().next().unwrap();
+            (virus.x, virus.y)
+        };
+        (game.cells[0].x, game.cells[0].y) = (x, y);
+        game.tick(0.0);
+        assert_eq!(game.cells.len(), 1, "too small to pop");
+
+        let mass = VIRUS_MASS * EAT_RATIO;
+        game.cells[0].mass = mass;
+        game.cells[0].radius = mass_to_radius(mass);
+        let viruses = game.viruses.len();
+        game.tick(0.0);
+
+        assert_eq!(game.cells.len(), 1 + POP_PIECES);
+        assert!(game.cells.iter().all(|cell| cell.owner == uid));
+        let total: f32 = game.cells.iter().map(|cell| cell.mass).sum();
+        assert!(total >= mass + VIRUS_MASS);
+        // A new virus grows back right away.
+        assert_eq!(game.viruses.len(), viruses);
+    }
+
+    #[test]
+    fn fed_viruses_shoot_new_ones() {
+        let mut virus = Virus::new(0.0, 0.0);
+        for _ in 1..VIRUS_FEED_COUNT {
+            assert!(virus.feed(1.0, 0.0).is_none());
+        }
+        assert!(virus.mass > VIRUS_MASS);
+
+        let shot = virus.feed(1.0, 0.0).unwrap();
+        assert_eq!(virus.mass, VIRUS_MASS);
+        assert!(shot.x > virus.radius && shot.vx == VIRUS_SHOT_SPEED);
+    }
+
+    // More players and food than a real game has, spread over the map.
+    fn crowded_game(players: usize, food: usize) -> Game {
+        use rand::Rng;
+
+        let mut game = new_game();
+        let mut rng = rand::thread_rng();
+        for _ in 0..players {
+            game.add_player(String::new());
+        }
+        for cell in game.cells.iter_mut() {
+            (cell.x, cell.y) = gen_food();
+            cell.steer = Steer::Direction(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+        }
+        game.index_cells();
+        while game.food.len() < food {
+            game.spawn_food();
+        }
+        game
+    }
+
+    // Benchmark of a whole tick including every client's update, run it
+    // with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn tick_stays_within_budget() {
+        let mut game = crowded_game(1000, 10000);
+        let mut views: Vec<_> = game
+            .cells
+            .iter()
+            .map(|cell| {
+                let rect = game.player_view(cell.uid).unwrap();
+                ClientView::new(cell.uid, rect, Snapshot::from_world(&[], &[]))
+            })
+            .collect();
+
+        let dt = 1.0 / common::TICK_RATE as f32;
+        let ticks = 100;
+        let start = std::time::Instant::now();
+        for _ in 0..ticks {
+            game.tick(dt);
+            for view in views.iter_mut() {
+                view.update(&game);
+                let seq = view.latest().seq;
+                view.ack(seq);
             }
-        })
-        .await
+        }
+        let elapsed = start.elapsed() / ticks;
+
+        let budget = Duration::from_secs_f32(dt);
+        println!("{:?} per tick, the budget is {:?}", elapsed, budget);
+        assert!(elapsed < budget);
+    }
+
+    #[test]
+    fn snapshots_only_contain_the_view() {
+        let game = new_game();
+        let rect = Rect::around(0.0, 0.0, CAMERA_WIDTH, CAMERA_HEIGHT);
+        let snapshot = game.snapshot(rect);
+
+        assert!(snapshot.food.len() < game.food.len());
+        for (x, y) in snapshot.food.values() {
+            assert!(rect.contains(*x, *y));
+        }
+    }
 }
@@ -27,15 +27,22 @@ fn main() {
             }),
             ..default()
         }))
-        .add_event::<common::Message>()
+        .add_event::<systems::ServerEvent>()
         .insert_resource(Msaa::default())
         .insert_resource(ClearColor(Color::WHITE))
+        // Inputs are sent and predicted at the rate the server ticks at.
+        .insert_resource(FixedTime::new_from_secs(1.0 / common::TICK_RATE as f32))
         .add_startup_system(setup)
         .add_system(read_events)
         .add_system(spawn_food)
         .add_system(player_movement)
         .add_system(enemy_movement)
         .add_system(player_movement_mouse)
+        .add_system(player_split)
+        .add_system(player_eject)
+        .add_system(camera_follow)
+        .add_system(player_prediction.in_schedule(CoreSchedule::FixedUpdate))
+        .add_system(player_smoothing)
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
         .run();
 }
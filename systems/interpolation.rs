@@ -0,0 +1,87 @@
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+// Seconds between two snapshots from the server.
+const TICK: f64 = 1.0 / common::TICK_RATE as f64;
+
+// How other players' cells are rendered from the positions the server
+// sends. Insert it before `setup` runs to change the defaults.
+#[derive(Resource)]
+pub struct Interpolation {
+    // Seconds in the past cells are shown at, so that there usually is a
+    // newer position to move toward.
+    pub delay: f64,
+    // Seconds past the newest position a cell keeps going with its last
+    // velocity when updates are late.
+    pub max_extrapolation: f64,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Self {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    time: f64,
+    position: Vec2,
+    velocity: Vec2,
+}
+
+// Positions of a remote cell by the time they were received.
+#[derive(Component)]
+pub struct Samples(VecDeque<Sample>);
+
+impl Samples {
+    pub fn new(time: f64, position: Vec2) -> Self {
+        let sample = Sample {
+            time,
+            position,
+            velocity: Vec2::ZERO,
+        };
+        Self(VecDeque::from([sample]))
+    }
+
+    pub fn push(&mut self, time: f64, position: Vec2, velocity: Vec2) {
+        // Cells are only sent when they change, one that stood still starts
+        // moving from where it was a tick ago instead of sliding all the
+        // way from when it stopped.
+        if let Some(last) = self.0.back().copied() {
+            if time - last.time > TICK {
+                self.0.push_back(Sample {
+                    time: time - TICK,
+                    velocity: Vec2::ZERO,
+                    ..last
+                });
+            }
+        }
+        self.0.push_back(Sample {
+            time,
+            position,
+            velocity,
+        });
+    }
+
+    // Where the cell was at `time`, samples before that are dropped.
+    pub fn at(&mut self, time: f64, max_extrapolation: f64) -> Vec2 {
+        while self.0.len() > 1 && self.0[1].time <= time {
+            self.0.pop_front();
+        }
+        let from = self.0[0];
+        match self.0.get(1) {
+            Some(to) if time > from.time => {
+                let t = (time - from.time) / (to.time - from.time);
+                from.position.lerp(to.position, t as f32)
+            }
+            Some(_) => from.position,
+            None => {
+                let ahead = (time - from.time).clamp(0.0, max_extrapolation);
+                from.position + from.velocity * ahead as f32
+            }
+        }
+    }
+}
@@ -1,21 +1,32 @@
-use bevy::input::mouse::{MouseButtonInput, MouseMotion, MouseWheel};
+use bevy::input::mouse::{MouseButtonInput, MouseScrollUnit, MouseWheel};
 use bevy::sprite::{MaterialMesh2dBundle, Mesh2dHandle};
+use bevy::window::PrimaryWindow;
 use bevy::{prelude::*, render::mesh::PrimitiveTopology, window::WindowResolution};
 use bevy_rapier2d::prelude::*;
-use common::Message;
-use crossbeam_channel::{bounded, Receiver, Sender};
+use common::{CellInfo, Message, Steer, CAMERA_WIDTH, PELLET_RADIUS};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use std::collections::HashMap;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
+mod interpolation;
 mod net;
+mod prediction;
 
-#[derive(Resource, Deref)]
+pub use interpolation::Interpolation;
+pub use net::{ConnectionState, ServerEvent};
+use interpolation::Samples;
+use prediction::{Inputs, Prediction};
+
+#[derive(Resource)]
 pub struct ServerEvents {
-    rx: Receiver<Message>,
+    rx: Receiver<ServerEvent>,
+    // Changes of the connection, latest last.
+    states: Receiver<ConnectionState>,
 }
 
 impl ServerEvents {
-    pub fn new(rx: Receiver<Message>) -> Self {
-        Self { rx }
+    pub fn new(rx: Receiver<ServerEvent>, states: Receiver<ConnectionState>) -> Self {
+        Self { rx, states }
     }
 }
 
@@ -32,43 +43,250 @@ impl PlayerTx {
 
 fn connect() -> (ServerEvents, PlayerTx) {
     let (tx, rx) = bounded(100);
+    let (state_tx, state_rx) = unbounded();
     let (player_tx, player_rx) = mpsc::unbounded_channel();
     std::thread::spawn(move || {
-        // ...
+        // The network runs on its own thread, reconnecting as needed.
         tokio::runtime::Runtime::new()
             .unwrap()
-            .block_on(async move {
-                net::connect(tx, player_rx).await.unwrap();
-            });
+            .block_on(net::run(tx, state_tx, player_rx));
     });
-    (ServerEvents::new(rx), PlayerTx::new(player_tx))
+    (ServerEvents::new(rx, state_rx), PlayerTx::new(player_tx))
 }
 
 #[derive(Component)]
 pub struct Player {
     // The float value is the player movement speed in 'pixels/second'.
     pub speed: f32,
-     // Previous vector of the player motion. Used to calculate new
-    // motion when there is change in only one axis, eg: mouse motion.
-    prev_force: Vec2,  
     uid: u32,
 }
 
+// The local player was eaten, it no longer moves or sends input.
+#[derive(Component)]
+pub struct Dead;
+
 #[derive(Component)]
 pub struct Enemy {
     // The float value is the player movement speed in 'pixels/second'.
     pub speed: f32,
+    pub name: String,
 
     uid: u32,
 }
 
+// Food entities by their server id.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct Food(HashMap<u32, Entity>);
+
+// Ejected pellets by their server id.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct Pellets(HashMap<u32, Entity>);
+
+// Viruses by their server id.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct Viruses(HashMap<u32, Entity>);
+
+// uid of the local player, its cells are the `Player` entities.
+#[derive(Resource, Deref)]
+pub struct LocalPlayer(u32);
+
+// Size of the map as told by the server when joining.
+#[derive(Resource, Deref)]
+pub struct MapSize(Vec2);
+
+// Zoom picked with the mouse wheel, on top of the zoom for the player's
+// mass. Larger is further out.
+#[derive(Resource, Deref, DerefMut)]
+pub struct CameraZoom(f32);
+
+impl Default for CameraZoom {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+// Grid lines and walls around the map, replaced with every snapshot.
+#[derive(Component)]
+pub struct Map;
+
 static DEFAULT_SPEED: f32 = 2000.0;
+// Radius of the player meshes, cells are scaled relative to it.
+static PLAYER_RADIUS: f32 = 50.0;
+// Distance between grid lines and how thick the walls around the map are.
+static GRID_STEP: f32 = 100.0;
+static WALL_THICKNESS: f32 = 50.0;
+// Bounds of the mouse wheel zoom, and how much one wheel line zooms.
+static MIN_ZOOM: f32 = 0.5;
+static MAX_ZOOM: f32 = 2.0;
+static ZOOM_STEP: f32 = 1.1;
+// How quickly the camera catches up with the player, per second.
+static CAMERA_SMOOTHING: f32 = 5.0;
+
+fn cell_uid((player, enemy): (Option<&Player>, Option<&Enemy>)) -> Option<u32> {
+    player.map(|player| player.uid).or(enemy.map(|enemy| enemy.uid))
+}
+
+// Spawn a small red circle at the given position.
+fn spawn_food_globule(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    x: f32,
+    y: f32,
+) -> Entity {
+    commands
+        .spawn((
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(
+                    meshes.add(
+                        shape::Circle {
+                            radius: 10.0,
+                            ..Default::default()
+                        }
+                        .into(),
+                    ),
+                ),
+                transform: Transform::from_translation(Vec3::new(x, y, 1.0)),
+                material: materials.add(Color::RED.into()),
+                ..Default::default()
+            },
+            // Eating happens on the server, don't push food around.
+            Collider::ball(10.0),
+            Sensor,
+        ))
+        .id()
+}
+
+// Spawn an ejected pellet, an orange hexagon so it doesn't look like food.
+fn spawn_pellet(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    x: f32,
+    y: f32,
+) -> Entity {
+    commands
+        .spawn((
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(shape::RegularPolygon::new(PELLET_RADIUS, 6).into())),
+                transform: Transform::from_translation(Vec3::new(x, y, 1.0)),
+                material: materials.add(Color::ORANGE.into()),
+                ..Default::default()
+            },
+            Collider::ball(PELLET_RADIUS),
+            Sensor,
+        ))
+        .id()
+}
+
+// Spawn a virus, drawn above cells so that small ones hide under it.
+fn spawn_virus(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    x: f32,
+    y: f32,
+    radius: f32,
+) -> Entity {
+    commands
+        .spawn((
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(Mesh::from(Spikes {
+                    radius: PLAYER_RADIUS,
+                    count: 24,
+                }))),
+                transform: Transform::from_translation(Vec3::new(x, y, 2.0))
+                    .with_scale(Vec3::splat(radius / PLAYER_RADIUS)),
+                material: materials.add(Color::rgb(0.2, 0.8, 0.2).into()),
+                ..Default::default()
+            },
+            Collider::ball(PLAYER_RADIUS),
+            Sensor,
+        ))
+        .id()
+}
+
+// Spawn one of the local player's cells.
+fn spawn_player(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    x: f32,
+    y: f32,
+    radius: f32,
+    uid: u32,
+) {
+    let sprite_size = PLAYER_RADIUS * 2.0;
+
+    commands.spawn((
+        MaterialMesh2dBundle {
+            mesh: Mesh2dHandle(
+                meshes.add(
+                    shape::Circle {
+                        radius: sprite_size / 2.0,
+                        ..Default::default()
+                    }
+                    .into(),
+                ),
+            ),
+            transform: Transform::from_translation(Vec3::new(x, y, 1.0))
+                .with_scale(Vec3::splat(radius / PLAYER_RADIUS)),
+            material: materials.add(Color::GREEN.into()),
+            ..Default::default()
+        },
+        // Cells are moved by the server and prediction, not by physics.
+        Collider::ball(sprite_size / 2.0),
+        Sensor,
+        Player {
+            speed: DEFAULT_SPEED,
+            uid,
+        },
+        Prediction::new(Vec2::new(x, y), radius),
+    ));
+}
+
+// Spawn another player at the position it currently has on the server,
+// received at `time`.
+fn spawn_enemy(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    info: &CellInfo,
+    time: f64,
+) {
+    let sprite_size = PLAYER_RADIUS * 2.0;
+    let color = Color::rgb_u8((info.color >> 16) as u8, (info.color >> 8) as u8, info.color as u8);
+
+    commands.spawn((
+        MaterialMesh2dBundle {
+            mesh: Mesh2dHandle(
+                meshes.add(
+                    shape::Circle {
+                        radius: sprite_size / 2.0,
+                        ..Default::default()
+                    }
+                    .into(),
+                ),
+            ),
+            transform: Transform::from_translation(Vec3::new(info.x, info.y, 1.0))
+                .with_scale(Vec3::splat(info.radius / PLAYER_RADIUS)),
+            material: materials.add(color.into()),
+            ..Default::default()
+        },
+        Collider::ball(sprite_size / 2.0),
+        Sensor,
+        Enemy {
+            speed: DEFAULT_SPEED,
+            name: info.name.to_string_lossy().into_owned(),
+            uid: info.uid,
+        },
+        Samples::new(time, Vec2::new(info.x, info.y)),
+    ));
+}
 
 #[no_mangle]
 pub fn setup(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
     mut rapier_config: ResMut<RapierConfiguration>,
 ) {
     // Set gravity to 0.0 and spawn camera.
@@ -77,29 +295,67 @@ pub fn setup(
     let (server_events, player_tx) = connect();
     commands.insert_resource(server_events);
     commands.insert_resource(player_tx);
+    commands.init_resource::<Food>();
+    commands.init_resource::<Pellets>();
+    commands.init_resource::<Viruses>();
+    commands.init_resource::<CameraZoom>();
+    commands.init_resource::<Interpolation>();
+    commands.init_resource::<Inputs>();
+    commands.init_resource::<ConnectionState>();
     commands.spawn(Camera2dBundle::default());
+}
 
+// Grid lines over the whole map, which is centered around the origin, and
+// walls along its edges. Only drawn, the server and prediction keep cells
+// inside the map.
+fn spawn_map(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    size: Vec2,
+) {
+    let half = size / 2.0;
     let mut lines: Vec<(Vec3, Vec3)> = Vec::new();
-    // Grid lines all over the screen
-    let size = 1000.0;
-    let step = 100.0;
-    for i in -((size / step) as i32)..((size / step) as i32) {
-        let i = i as f32;
-        lines.push((
-            Vec3::new(i * step, -size, 0.0),
-            Vec3::new(i * step, size, 0.0),
-        ));
-        lines.push((
-            Vec3::new(-size, i * step, 0.0),
-            Vec3::new(size, i * step, 0.0),
-        ));
+    for i in 0..=(size.x / GRID_STEP) as i32 {
+        let x = -half.x + i as f32 * GRID_STEP;
+        lines.push((Vec3::new(x, -half.y, 0.0), Vec3::new(x, half.y, 0.0)));
     }
+    for i in 0..=(size.y / GRID_STEP) as i32 {
+        let y = -half.y + i as f32 * GRID_STEP;
+        lines.push((Vec3::new(-half.x, y, 0.0), Vec3::new(half.x, y, 0.0)));
+    }
+    commands.spawn((
+        MaterialMesh2dBundle {
+            mesh: Mesh2dHandle(meshes.add(Mesh::from(LineList { lines }))),
+            material: materials.add(Color::BLACK.into()),
+            ..default()
+        },
+        Map,
+    ));
 
-    commands.spawn(MaterialMesh2dBundle {
-        mesh: Mesh2dHandle(meshes.add(Mesh::from(LineList { lines }))),
-        material: materials.add(Color::BLACK.into()),
-        ..default()
-    });
+    // Walls sit just outside the map, the horizontal ones also cover the
+    // corners.
+    let offset = WALL_THICKNESS / 2.0;
+    let horizontal = Vec2::new(size.x + 2.0 * WALL_THICKNESS, WALL_THICKNESS);
+    let vertical = Vec2::new(WALL_THICKNESS, size.y);
+    let walls = [
+        (Vec2::new(0.0, half.y + offset), horizontal),
+        (Vec2::new(0.0, -half.y - offset), horizontal),
+        (Vec2::new(half.x + offset, 0.0), vertical),
+        (Vec2::new(-half.x - offset, 0.0), vertical),
+    ];
+    let material = materials.add(Color::DARK_GRAY.into());
+    for (center, extents) in walls {
+        commands.spawn((
+            MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(shape::Quad::new(extents).into())),
+                transform: Transform::from_translation(center.extend(0.0)),
+                material: material.clone(),
+                ..default()
+            },
+            Map,
+        ));
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -107,6 +363,35 @@ pub struct LineList {
     pub lines: Vec<(Vec3, Vec3)>,
 }
 
+// A circle with spikes all around it, the spikes reach `radius`.
+#[derive(Debug, Clone)]
+pub struct Spikes {
+    pub radius: f32,
+    pub count: usize,
+}
+
+impl From<Spikes> for Mesh {
+    fn from(spikes: Spikes) -> Self {
+        // Every spike is a triangle from the center to its tip, and one
+        // from the center to the notch before the next spike.
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+
+        let inner = spikes.radius * 0.85;
+        let points: Vec<_> = (0..spikes.count * 2)
+            .map(|i| {
+                let angle = i as f32 / (spikes.count * 2) as f32 * std::f32::consts::TAU;
+                let radius = if i % 2 == 0 { inner } else { spikes.radius };
+                Vec3::new(angle.cos() * radius, angle.sin() * radius, 0.0)
+            })
+            .collect();
+        let vertices: Vec<_> = (0..points.len())
+            .flat_map(|i| [Vec3::ZERO, points[i], points[(i + 1) % points.len()]])
+            .collect();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vertices);
+        mesh
+    }
+}
+
 impl From<LineList> for Mesh {
     fn from(line: LineList) -> Self {
         // This tells wgpu that the positions are list of lines
@@ -120,188 +405,405 @@ impl From<LineList> for Mesh {
 }
 
 #[no_mangle]
-pub fn read_events(receiver: Res<ServerEvents>, mut events: EventWriter<Message>) {
-    for msg in receiver.try_iter() {
+pub fn read_events(
+    receiver: Res<ServerEvents>,
+    mut events: EventWriter<ServerEvent>,
+    mut connection: ResMut<ConnectionState>,
+) {
+    if let Some(state) = receiver.states.try_iter().last() {
+        *connection = state;
+    }
+    for msg in receiver.rx.try_iter() {
         events.send(msg);
     }
 }
 
 #[no_mangle]
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_food(
     mut commands: Commands,
-    mut reader: EventReader<Message>,
+    mut reader: EventReader<ServerEvent>,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut enemy_info: Query<(&mut Enemy, &mut Velocity, &mut ExternalForce)>,
+    mut food: ResMut<Food>,
+    mut pellets: ResMut<Pellets>,
+    mut viruses: ResMut<Viruses>,
+    mut cells: Query<(Entity, &mut Transform, AnyOf<(&Player, &Enemy)>)>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    local: Option<Res<LocalPlayer>>,
+    maps: Query<Entity, With<Map>>,
+    mut samples: Query<(&Enemy, &mut Samples)>,
+    mut predictions: Query<(&Player, &mut Prediction)>,
+    mut inputs: ResMut<Inputs>,
+    time: Res<Time>,
 ) {
+    let now = time.elapsed_seconds_f64();
+    // Our cells can enter the view in the same frame as the `Start`.
+    let mut local = local.map(|local| **local);
     for (per_frame, event) in reader.iter().enumerate() {
         match event {
-            Message::SpawnFood(x, y) => {
-                // Spawn a small red circle at the given position.
-                commands.spawn((
-                    MaterialMesh2dBundle {
-                        mesh: Mesh2dHandle(
-                            meshes.add(
-                                shape::Circle {
-                                    radius: 10.0,
-                                    ..Default::default()
-                                }
-                                .into(),
-                            ),
-                        ),
-                        transform: Transform::from_translation(Vec3::new(*x, *y, 1.0)),
-                        material: materials.add(Color::RED.into()),
-                        ..Default::default()
-                    },
-                    RigidBody::Dynamic,
-                    Collider::ball(10.0),
-                ));
+            ServerEvent::Message(Message::SpawnFood(x, y, id)) => {
+                let entity = spawn_food_globule(&mut commands, &mut meshes, &mut materials, *x, *y);
+                food.insert(*id, entity);
             }
-            Message::Start(x, y, uid) => {
-                let sprite_size = 100.0;
-
-                commands.spawn((
-                    MaterialMesh2dBundle {
-                        mesh: Mesh2dHandle(
-                            meshes.add(
-                                shape::Circle {
-                                    radius: sprite_size / 2.0,
-                                    ..Default::default()
-                                }
-                                .into(),
-                            ),
-                        ),
-                        transform: Transform::from_translation(Vec3::new(0.0, 0.0, 1.0)),
-                        material: materials.add(Color::GREEN.into()),
-                        ..Default::default()
-                    },
-                    RigidBody::Dynamic,
-                    Velocity::linear(Vec2::new(0.0, 0.0)),
-                    ExternalForce {
-                        force: Vec2::new(0.0, 0.0),
-                        torque: 0.0,
-                    },
-                    Collider::ball(sprite_size / 2.0),
-                    Player {
-                        speed: DEFAULT_SPEED,
-                        prev_force: Vec2::ZERO,
-                        uid: *uid,
-                    },
-                ));
+            ServerEvent::Message(Message::WorldSnapshot {
+                map_size,
+                food: snapshot_food,
+                cells: snapshot_cells,
+                ..
+            }) => {
+                // The snapshot replaces whatever we knew about the world,
+                // after reconnecting that includes our own cells and their
+                // predictions. Inputs are numbered from the start again.
+                inputs.reset();
+                let entities = food.drain().chain(pellets.drain()).chain(viruses.drain());
+                for (_, entity) in entities {
+                    commands.entity(entity).despawn();
+                }
+                for (entity, ..) in cells.iter() {
+                    commands.entity(entity).despawn();
+                }
+
+                for entity in maps.iter() {
+                    commands.entity(entity).despawn();
+                }
+                let size = Vec2::new(map_size.0, map_size.1);
+                spawn_map(&mut commands, &mut meshes, &mut materials, size);
+                commands.insert_resource(MapSize(size));
+                for info in snapshot_food {
+                    let entity =
+                        spawn_food_globule(&mut commands, &mut meshes, &mut materials, info.x, info.y);
+                    food.insert(info.id, entity);
+                }
+                for info in snapshot_cells {
+                    spawn_enemy(&mut commands, &mut meshes, &mut materials, info, now);
+                }
             }
-            Message::NewPlayer(x, y, uid) => {
-                // Spawn a new player at the given position.
-                let sprite_size = 100.0;
-
-                commands.spawn((
-                    MaterialMesh2dBundle {
-                        mesh: Mesh2dHandle(
-                            meshes.add(
-                                shape::Circle {
-                                    radius: sprite_size / 2.0,
-                                    ..Default::default()
-                                }
-                                .into(),
-                            ),
-                        ),
-                        transform: Transform::from_translation(Vec3::new(200.0, 200.0, 1.0)),
-                        material: materials.add(Color::YELLOW.into()),
-                        ..Default::default()
-                    },
-                    RigidBody::Dynamic,
-                    Velocity::linear(Vec2::new(0.0, 0.0)),
-                    ExternalForce {
-                        force: Vec2::new(0.0, 0.0),
-                        torque: 0.0,
-                    },
-                    Collider::ball(sprite_size / 2.0),
-                    Enemy {
-                        speed: DEFAULT_SPEED,
-                        uid: *uid,
-                    },
-                ));
+            ServerEvent::FoodEaten(id) => {
+                if let Some(entity) = food.remove(id) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerEvent::UpdatePellet(x, y, id) => match pellets.get(id) {
+                Some(entity) => {
+                    commands
+                        .entity(*entity)
+                        .insert(Transform::from_translation(Vec3::new(*x, *y, 1.0)));
+                }
+                None => {
+                    let entity = spawn_pellet(&mut commands, &mut meshes, &mut materials, *x, *y);
+                    pellets.insert(*id, entity);
+                }
+            },
+            ServerEvent::UpdateVirus(x, y, radius, id) => match viruses.get(id) {
+                Some(entity) => {
+                    let transform = Transform::from_translation(Vec3::new(*x, *y, 2.0))
+                        .with_scale(Vec3::splat(radius / PLAYER_RADIUS));
+                    commands.entity(*entity).insert(transform);
+                }
+                None => {
+                    let (x, y, radius) = (*x, *y, *radius);
+                    let entity =
+                        spawn_virus(&mut commands, &mut meshes, &mut materials, x, y, radius);
+                    viruses.insert(*id, entity);
+                }
+            },
+            ServerEvent::VirusRemoved(id) => {
+                if let Some(entity) = viruses.remove(id) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerEvent::PelletEaten(id) => {
+                if let Some(entity) = pellets.remove(id) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            ServerEvent::CellGrew(uid, radius) => {
+                for (_, mut transform, cell) in cells.iter_mut() {
+                    if cell_uid(cell) == Some(*uid) {
+                        transform.scale = Vec3::splat(radius / PLAYER_RADIUS);
+                    }
+                }
+            }
+            ServerEvent::Message(Message::Start(uid, _)) => {
+                // Our cells are spawned as they enter the view.
+                commands.insert_resource(LocalPlayer(*uid));
+                local = Some(*uid);
             }
-            Message::MovePlayer(force_x, force_y, uid) => {
-                // Move the player by applying a force to it.
-                for (mut player, mut vel, mut force) in enemy_info.iter_mut() {
+            ServerEvent::Message(Message::EnterView(info)) => {
+                // Cells of the local player are ours to steer.
+                if local == Some(info.owner) {
+                    let (x, y, radius) = (info.x, info.y, info.radius);
+                    spawn_player(&mut commands, &mut meshes, &mut materials, x, y, radius, info.uid);
+                } else {
+                    spawn_enemy(&mut commands, &mut meshes, &mut materials, info, now);
+                }
+            }
+            ServerEvent::UpdatePlayer(x, y, vx, vy, uid) => {
+                // The server owns positions, predictions start over from
+                // them with the `InputAck` that follows.
+                for (player, mut prediction) in predictions.iter_mut() {
                     if player.uid == *uid {
-                        let move_delta = Vec2::new(*force_x, *force_y);
-                        force.force = move_delta * player.speed;
-                        vel.linvel = Vec2::ZERO;
+                        prediction.update(Vec2::new(*x, *y), Vec2::new(*vx, *vy));
+                    }
+                }
+                // Other players are rendered from their recent positions.
+                for (enemy, mut samples) in samples.iter_mut() {
+                    if enemy.uid == *uid {
+                        samples.push(now, Vec2::new(*x, *y), Vec2::new(*vx, *vy));
+                    }
+                }
+            }
+            ServerEvent::Message(Message::PlayerDied(uid)) => {
+                for (entity, _, (player, enemy)) in cells.iter() {
+                    if player.map(|player| player.uid) == Some(*uid) {
+                        commands.entity(entity).insert((Dead, Visibility::Hidden));
+                    } else if enemy.map(|enemy| enemy.uid) == Some(*uid) {
+                        commands.entity(entity).despawn();
                     }
                 }
             }
+            // Our own split cells can leave the view too, they are spawned
+            // again when they come back.
+            ServerEvent::Message(
+                Message::CellRemoved(uid) | Message::PlayerLeft(uid) | Message::LeaveView(uid),
+            ) => {
+                for (entity, _, cell) in cells.iter() {
+                    if cell_uid(cell) == Some(*uid) {
+                        commands.entity(entity).despawn();
+                    }
+                }
+            }
+            ServerEvent::InputAck(seq) => {
+                inputs.ack(*seq);
+                for (_, mut prediction) in predictions.iter_mut() {
+                    prediction.reconcile(&inputs);
+                }
+            }
+            // Inputs, the handshake and deltas are handled by the network thread.
+            ServerEvent::Message(
+                Message::MovePlayer(..)
+                | Message::MoveTo(..)
+                | Message::Split(..)
+                | Message::Eject(..)
+                | Message::Hello { .. }
+                | Message::Welcome { .. }
+                | Message::Delta(_)
+                | Message::Ack(_),
+            ) => {}
         }
     }
 }
 
+// W ejects mass, the letters are left alone.
+static MOVEMENT_KEYS: [KeyCode; 4] = [KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right];
+
+#[no_mangle]
+pub fn player_movement(keyboard_input: Res<Input<KeyCode>>, mut inputs: ResMut<Inputs>) {
+    let keys = MOVEMENT_KEYS;
+    // Only change the direction when the keys do, the mouse steers otherwise.
+    if !keyboard_input.any_just_pressed(keys) && !keyboard_input.any_just_released(keys) {
+        return;
+    }
+
+    // Every cell of the player follows the same direction.
+    let up = keyboard_input.pressed(KeyCode::Up);
+    let down = keyboard_input.pressed(KeyCode::Down);
+    let left = keyboard_input.pressed(KeyCode::Left);
+    let right = keyboard_input.pressed(KeyCode::Right);
+
+    let x_axis = -(left as i8) + right as i8;
+    let y_axis = -(down as i8) + up as i8;
+
+    let mut move_delta = Vec2::new(x_axis as f32, y_axis as f32);
+    if move_delta != Vec2::ZERO {
+        move_delta /= move_delta.length();
+    }
+
+    // Sent with the next tick by `player_prediction`.
+    inputs.current = Steer::Direction(move_delta.x, move_delta.y);
+}
+
+// Where the mouse cursor points at on the map.
+fn cursor_position(
+    windows: &Query<&Window, With<PrimaryWindow>>,
+    camera: &Query<(&Camera, &GlobalTransform)>,
+) -> Option<Vec2> {
+    let cursor = windows.get_single().ok()?.cursor_position()?;
+    let (camera, camera_transform) = camera.single();
+    Some(camera.viewport_to_world(camera_transform, cursor)?.origin.truncate())
+}
+
+// Direction from the middle of the player's cells to the mouse cursor.
+fn cursor_direction(
+    player_info: &Query<(&Player, &Transform), Without<Dead>>,
+    windows: &Query<&Window, With<PrimaryWindow>>,
+    camera: &Query<(&Camera, &GlobalTransform)>,
+) -> Option<Vec2> {
+    let target = cursor_position(windows, camera)?;
+
+    let count = player_info.iter().len();
+    if count == 0 {
+        return None;
+    }
+    let center = player_info
+        .iter()
+        .map(|(_, transform)| transform.translation.truncate())
+        .sum::<Vec2>()
+        / count as f32;
+    Some((target - center).normalize_or_zero())
+}
+
+// Splits the player's cells toward the mouse cursor.
 #[no_mangle]
-pub fn player_movement(
+pub fn player_split(
     keyboard_input: Res<Input<KeyCode>>,
-    mut player_info: Query<(&Player, &mut Velocity)>,
+    player_info: Query<(&Player, &Transform), Without<Dead>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    player_tx: Res<PlayerTx>,
 ) {
-    for (player, mut rb_vels) in &mut player_info {
-        let up = keyboard_input.any_pressed([KeyCode::W, KeyCode::Up]);
-        let down = keyboard_input.any_pressed([KeyCode::S, KeyCode::Down]);
-        let left = keyboard_input.any_pressed([KeyCode::A, KeyCode::Left]);
-        let right = keyboard_input.any_pressed([KeyCode::D, KeyCode::Right]);
-
-        let x_axis = -(left as i8) + right as i8;
-        let y_axis = -(down as i8) + up as i8;
-
-        let mut move_delta = Vec2::new(x_axis as f32, y_axis as f32);
-        if move_delta != Vec2::ZERO {
-            move_delta /= move_delta.length();
-        }
+    if !keyboard_input.just_pressed(KeyCode::Space) {
+        return;
+    }
+    if let Some(dir) = cursor_direction(&player_info, &windows, &camera) {
+        let (player, _) = player_info.iter().next().unwrap();
+        player_tx
+            .tx
+            .send(Message::Split(player.uid, dir.x, dir.y))
+            .unwrap();
+    }
+}
 
-        // Update the velocity on the rigid_body_component,
-        // the bevy_rapier plugin will update the Sprite transform.
-        rb_vels.linvel = move_delta * player.speed;
+// Ejects a pellet of mass from each of the player's cells toward the
+// mouse cursor.
+#[no_mangle]
+pub fn player_eject(
+    keyboard_input: Res<Input<KeyCode>>,
+    player_info: Query<(&Player, &Transform), Without<Dead>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    player_tx: Res<PlayerTx>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::W) {
+        return;
+    }
+    if let Some(dir) = cursor_direction(&player_info, &windows, &camera) {
+        let (player, _) = player_info.iter().next().unwrap();
+        player_tx
+            .tx
+            .send(Message::Eject(player.uid, dir.x, dir.y))
+            .unwrap();
     }
 }
 
+// Moves other players' cells to where they were `Interpolation::delay`
+// ago, between the positions the server sent around that time.
 #[no_mangle]
 pub fn enemy_movement(
-  mut enemy_info: Query<(&Enemy, &mut Velocity)>,
+    time: Res<Time>,
+    interpolation: Res<Interpolation>,
+    mut enemy_info: Query<(&mut Transform, &mut Samples), With<Enemy>>,
 ) {
-     for (enemy, mut rb_vels) in &mut enemy_info {
-        rb_vels.linvel = Vec2::ZERO;
+    let render_time = time.elapsed_seconds_f64() - interpolation.delay;
+    for (mut transform, mut samples) in &mut enemy_info {
+        let position = samples.at(render_time, interpolation.max_extrapolation);
+        transform.translation = position.extend(transform.translation.z);
     }
 }
 
-// Player movement that follows the mouse cursor.
+// Keeps the camera on the centroid of the player's cells, zoomed out as
+// far as the server lets the player see for their total mass.
+#[no_mangle]
+pub fn camera_follow(
+    time: Res<Time>,
+    mut wheel_events: EventReader<MouseWheel>,
+    mut zoom: ResMut<CameraZoom>,
+    player_info: Query<&Transform, (With<Player>, Without<Dead>)>,
+    mut camera: Query<(&mut Transform, &mut OrthographicProjection), Without<Player>>,
+) {
+    for event in wheel_events.iter() {
+        let lines = match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / 100.0,
+        };
+        **zoom = (**zoom * ZOOM_STEP.powf(-lines)).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    let count = player_info.iter().len();
+    if count == 0 {
+        return;
+    }
+    let center = player_info
+        .iter()
+        .map(|transform| transform.translation.truncate())
+        .sum::<Vec2>()
+        / count as f32;
+    // The view is sized from the radius all the mass would have as a
+    // single cell, like on the server.
+    let radius = player_info
+        .iter()
+        .map(|transform| (transform.scale.x * PLAYER_RADIUS).powi(2))
+        .sum::<f32>()
+        .sqrt();
+    let scale = common::view_size(radius).0 / CAMERA_WIDTH * **zoom;
+
+    let t = 1.0 - (-CAMERA_SMOOTHING * time.delta_seconds()).exp();
+    let (mut transform, mut projection) = camera.single_mut();
+    let target = center.extend(transform.translation.z);
+    transform.translation = transform.translation.lerp(target, t);
+    projection.scale += (scale - projection.scale) * t;
+}
+
+// Steers the player's cells toward the mouse cursor, the server slows
+// them down as they get close to it. The arrow keys take over while held.
 #[no_mangle]
 pub fn player_movement_mouse(
-    mut player_info: Query<(&mut Player, &mut Velocity, &mut ExternalForce)>,
-    mut player_tx: ResMut<PlayerTx>,
-    mut mouse_motion_events: EventReader<MouseMotion>,
+    keyboard_input: Res<Input<KeyCode>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    mut inputs: ResMut<Inputs>,
 ) {
-    for (mut player, mut rb_vels, mut force) in &mut player_info {
-        for event in mouse_motion_events.iter() {
-            let mut move_delta = event.delta;
-            // Inverse the y axis, because the mouse y axis is inverted.
-            move_delta.y = -move_delta.y;
-
-            if move_delta != Vec2::ZERO {
-                move_delta /= move_delta.length();
-                if move_delta.x == 0.0 {
-                    move_delta.x = player.prev_force.x;
-                }
-                if move_delta.y == 0.0 {
-                    move_delta.y = player.prev_force.y;
-                }
+    if keyboard_input.any_pressed(MOVEMENT_KEYS) {
+        return;
+    }
+    // The point under a still cursor moves along with the camera.
+    if let Some(target) = cursor_position(&windows, &camera) {
+        inputs.current = Steer::Target(target.x, target.y);
+    }
+}
 
-                force.force = move_delta * player.speed;
-                player.prev_force = move_delta.clone();
-                player_tx
-                    .tx
-                    .send(Message::MovePlayer(
-                        move_delta.x,
-                        move_delta.y,
-                        player.uid,
-                    ))
-                    .unwrap();
-            }
-        }
+// Sends the input once per server tick, numbered, and moves the player's
+// cells with it right away instead of waiting for the server.
+#[no_mangle]
+pub fn player_prediction(
+    mut inputs: ResMut<Inputs>,
+    mut player_info: Query<(&Player, &Transform, &mut Prediction), Without<Dead>>,
+    player_tx: Res<PlayerTx>,
+) {
+    let Some(uid) = player_info.iter().next().map(|(player, ..)| player.uid) else {
+        return;
+    };
+    let (seq, steer) = inputs.next();
+    player_tx
+        .tx
+        .send(prediction::input_message(steer, uid, seq))
+        .unwrap();
+    for (_, transform, mut prediction) in &mut player_info {
+        prediction.set_radius(transform.scale.x * PLAYER_RADIUS);
+        prediction.step(steer);
+    }
+}
+
+// Draws the player's cells between the last two predicted steps.
+#[no_mangle]
+pub fn player_smoothing(
+    time: Res<Time>,
+    fixed_time: Res<FixedTime>,
+    mut player_info: Query<(&mut Transform, &mut Prediction)>,
+) {
+    let progress = fixed_time.accumulated().as_secs_f32() / fixed_time.period.as_secs_f32();
+    for (mut transform, mut prediction) in &mut player_info {
+        let position = prediction.draw(progress.min(1.0), time.delta_seconds());
+        transform.translation = position.extend(transform.translation.z);
     }
 }
@@ -1,4 +1,6 @@
-use common::Message;
+use bevy::log::{error, info, warn};
+use bevy::prelude::Resource;
+use common::{Delta, Message, Snapshot, PROTOCOL_VERSION};
 use crossbeam_channel::Sender;
 use deku::prelude::*;
 use deku::DekuContainerRead;
@@ -8,14 +10,76 @@ use hyper::header::UPGRADE;
 use hyper::upgrade::Upgraded;
 use hyper::Body;
 use hyper::Request;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fmt;
 use std::future::Future;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 const SERVER_ADDR: &str = "localhost:8080";
 
+// Waits between attempts to connect, doubling after every failed one.
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+// The connection to the server as the game sees it.
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    // The connection dropped or could not be made, connecting is tried
+    // again after a while.
+    Disconnected,
+    // Connecting again would not help, eg: the server speaks another
+    // protocol version.
+    Error(String),
+}
+
+// An error reconnecting won't fix.
+#[derive(Debug)]
+struct Fatal(String);
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+// What the network thread hands to the game, the messages the server sent
+// and the changes it derived from each `Delta`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    Message(Message),
+    // x, y, vx, vy, uid
+    // Authoritative position and velocity of a cell.
+    UpdatePlayer(f32, f32, f32, f32, u32),
+    // id
+    FoodEaten(u32),
+    // uid, radius
+    CellGrew(u32, f32),
+    // x, y, id
+    // A pellet of ejected mass appeared or moved.
+    UpdatePellet(f32, f32, u32),
+    // id
+    PelletEaten(u32),
+    // x, y, radius, id
+    // A virus appeared, moved or grew.
+    UpdateVirus(f32, f32, f32, u32),
+    // id
+    VirusRemoved(u32),
+    // seq
+    // Last input the server applied before the positions that came with the
+    // same delta.
+    InputAck(u32),
+}
+
 struct SpawnExecutor;
 
 impl<Fut> hyper::rt::Executor<Fut> for SpawnExecutor
@@ -48,8 +112,160 @@ async fn ws_connect() -> Result<WebSocket<Upgraded>> {
     Ok(ws)
 }
 
-pub async fn connect(tx: Sender<Message>, mut player_rx: UnboundedReceiver<Message>) -> Result<()> {
+// Name shown to other players, taken from the environment.
+fn client_name() -> CString {
+    let name = std::env::var("CELL_IO_NAME").unwrap_or_default();
+    CString::new(name).unwrap_or_default()
+}
+
+// Close frames carry a 2 byte status code followed by the reason.
+fn close_reason(payload: &[u8]) -> String {
+    match payload.get(2..) {
+        Some(reason) if !reason.is_empty() => String::from_utf8_lossy(reason).into_owned(),
+        _ => "connection closed by server".to_string(),
+    }
+}
+
+// The server closes the connection when it won't let the client in, which
+// won't change by trying again.
+async fn handshake(ws: &mut WebSocket<Upgraded>, resume_token: u64) -> Result<()> {
+    let msg = Message::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: client_name(),
+        resume_token,
+    };
+    let frame = Frame::new(true, OpCode::Binary, None, msg.to_bytes().unwrap().into());
+    ws.write_frame(frame).await?;
+
+    let frame = ws.read_frame().await?;
+    match frame.opcode {
+        OpCode::Binary => match Message::try_from(frame.payload.as_ref())? {
+            Message::Welcome { server_version, .. } if server_version == PROTOCOL_VERSION => Ok(()),
+            Message::Welcome { server_version, .. } => Err(Fatal(format!(
+                "incompatible server protocol version {}, client speaks version {}",
+                server_version, PROTOCOL_VERSION
+            ))
+            .into()),
+            msg => Err(format!("expected a welcome message, got {:?}", msg).into()),
+        },
+        OpCode::Close => Err(Fatal(close_reason(&frame.payload)).into()),
+        _ => Err("expected a welcome message".into()),
+    }
+}
+
+// Snapshot the server builds its first delta against.
+fn baseline(msg: &Message) -> Option<Snapshot> {
+    let Message::WorldSnapshot { food, cells, .. } = msg else {
+        return None;
+    };
+    Some(Snapshot::from_world(food, cells))
+}
+
+// Applies a delta to the snapshot it was encoded against, and returns
+// the events that take the game from the latest snapshot to the new one.
+fn apply_delta(snapshots: &mut VecDeque<Snapshot>, delta: &Delta) -> Result<Vec<ServerEvent>> {
+    // The server moved on to a newer base, older snapshots are not needed.
+    while snapshots.front().is_some_and(|snapshot| snapshot.seq != delta.base) {
+        snapshots.pop_front();
+    }
+    let base = snapshots.front().ok_or("delta against an unknown snapshot")?;
+    let snapshot = base.apply(delta);
+    let latest = snapshots.back().unwrap();
+
+    let changes = latest.diff(&snapshot);
+    let mut events = Vec::new();
+    // Food coming into and going out of view is spawned and removed like
+    // any other.
+    for food in changes.food {
+        events.push(ServerEvent::Message(Message::SpawnFood(food.x, food.y, food.id)));
+    }
+    for id in changes.removed_food {
+        events.push(ServerEvent::FoodEaten(id));
+    }
+    for pellet in changes.pellets {
+        events.push(ServerEvent::UpdatePellet(pellet.x, pellet.y, pellet.id));
+    }
+    for id in changes.removed_pellets {
+        events.push(ServerEvent::PelletEaten(id));
+    }
+    for virus in changes.viruses {
+        events.push(ServerEvent::UpdateVirus(virus.x, virus.y, virus.radius, virus.id));
+    }
+    for id in changes.removed_viruses {
+        events.push(ServerEvent::VirusRemoved(id));
+    }
+    for cell in changes.cells {
+        let old = latest.cells.get(&cell.uid);
+        let motion = (cell.x, cell.y, cell.vx, cell.vy);
+        if old.map(|old| (old.x, old.y, old.vx, old.vy)) != Some(motion) {
+            events.push(ServerEvent::UpdatePlayer(cell.x, cell.y, cell.vx, cell.vy, cell.uid));
+        }
+        if old.map(|old| old.radius) != Some(cell.radius) {
+            events.push(ServerEvent::CellGrew(cell.uid, cell.radius));
+        }
+    }
+    // Cells that disappear died, left or went out of view, which are all
+    // sent as events.
+    events.push(ServerEvent::InputAck(snapshot.input));
+
+    snapshots.push_back(snapshot);
+    Ok(events)
+}
+
+// Keeps the game connected to the server, connecting again with a growing
+// backoff whenever the connection fails or drops.
+pub async fn run(
+    tx: Sender<ServerEvent>,
+    state: Sender<ConnectionState>,
+    mut player_rx: UnboundedReceiver<Message>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    // Reconnecting with the token from the last session resumes the player
+    // if the server still has its cells.
+    let mut token = 0;
+    let error = loop {
+        let _ = state.send(ConnectionState::Connecting);
+        let result = match open(token).await {
+            Ok(ws) => {
+                backoff = MIN_BACKOFF;
+                let _ = state.send(ConnectionState::Connected);
+                info!("Connected to {}", SERVER_ADDR);
+                // Inputs from while disconnected are stale.
+                while player_rx.try_recv().is_ok() {}
+                play(ws, &tx, &mut player_rx, &mut token).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Err(e) if e.is::<Fatal>() => break e,
+            Err(e) => warn!("Disconnected from server: {}, retrying in {:?}", e, backoff),
+            Ok(()) => warn!("Disconnected from server, retrying in {:?}", backoff),
+        }
+        let _ = state.send(ConnectionState::Disconnected);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    };
+
+    error!("Disconnected from server: {}", error);
+    let _ = state.send(ConnectionState::Error(error.to_string()));
+    // The game keeps sending inputs, nobody is listening anymore.
+    while player_rx.recv().await.is_some() {}
+}
+
+async fn open(token: u64) -> Result<WebSocket<Upgraded>> {
     let mut ws = ws_connect().await?;
+    handshake(&mut ws, token).await?;
+    Ok(ws)
+}
+
+// Runs a session until the connection drops.
+async fn play(
+    mut ws: WebSocket<Upgraded>,
+    tx: &Sender<ServerEvent>,
+    player_rx: &mut UnboundedReceiver<Message>,
+    token: &mut u64,
+) -> Result<()> {
+    let mut snapshots = VecDeque::new();
 
     loop {
         tokio::select! {
@@ -57,14 +273,43 @@ pub async fn connect(tx: Sender<Message>, mut player_rx: UnboundedReceiver<Messa
                 let frame = frame?;
                 match frame.opcode {
                   OpCode::Binary => {
-                    let msg = Message::try_from(frame.payload.as_ref())?;
-                    tx.send(msg)?;
+                    let mut acks = Vec::new();
+                    for msg in common::decode_batch(frame.payload.as_ref())? {
+                        if let Message::Delta(delta) = msg {
+                            for event in apply_delta(&mut snapshots, &delta)? {
+                                tx.send(event)?;
+                            }
+                            acks.push(Message::Ack(delta.seq));
+                            continue;
+                        }
+                        if let Some(snapshot) = baseline(&msg) {
+                            snapshots.clear();
+                            snapshots.push_back(snapshot);
+                        }
+                        if let Message::Start(_, start_token) = msg {
+                            *token = start_token;
+                        }
+                        tx.send(ServerEvent::Message(msg))?;
+                    }
+
+                    if !acks.is_empty() {
+                        let payload = common::encode_batch(&acks)?;
+                        let frame = Frame::new(true, OpCode::Binary, None, payload.into());
+                        ws.write_frame(frame).await?;
+                    }
                   },
+                  OpCode::Close => return Err(close_reason(&frame.payload).into()),
                   _ => {},
                 }
             }
             Some(msg) = player_rx.recv() => {
-                let frame = Frame::new(true, OpCode::Binary, None, msg.to_bytes().unwrap().into());
+                // Send everything the game queued up in a single frame.
+                let mut msgs = vec![msg];
+                while let Ok(msg) = player_rx.try_recv() {
+                    msgs.push(msg);
+                }
+                let payload = common::encode_batch(&msgs)?;
+                let frame = Frame::new(true, OpCode::Binary, None, payload.into());
                 ws.write_frame(frame).await?;
             }
         }
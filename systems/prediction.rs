@@ -0,0 +1,181 @@
+use bevy::prelude::*;
+use common::{Body, Message, Steer};
+use std::collections::VecDeque;
+
+// Seconds the server moves cells by every tick, inputs are sent as often.
+const TICK: f32 = 1.0 / common::TICK_RATE as f32;
+// Inputs the server never answers to are not kept forever.
+const MAX_PENDING_INPUTS: usize = 2 * common::TICK_RATE as usize;
+// How quickly what is drawn catches up with a corrected prediction.
+const CORRECTION_RATE: f32 = 10.0;
+
+// What is sent to the server for an input.
+pub fn input_message(steer: Steer, uid: u32, seq: u32) -> Message {
+    match steer {
+        Steer::Direction(x, y) => Message::MovePlayer(x, y, uid, seq),
+        Steer::Target(x, y) => Message::MoveTo(x, y, uid, seq),
+    }
+}
+
+// The player's input, and the ones sent that the server did not apply yet.
+#[derive(Resource)]
+pub struct Inputs {
+    // Sent again every tick.
+    pub current: Steer,
+    // The last one the server applied.
+    acked: Steer,
+    next_seq: u32,
+    pending: VecDeque<(u32, Steer)>,
+}
+
+impl Default for Inputs {
+    fn default() -> Self {
+        Self {
+            current: Steer::default(),
+            acked: Steer::default(),
+            // The server reports 0 before it got any input.
+            next_seq: 1,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Inputs {
+    // Numbers the current input for sending.
+    pub fn next(&mut self) -> (u32, Steer) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if self.pending.len() == MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((seq, self.current));
+        (seq, self.current)
+    }
+
+    // Forgets every input sent so far, the server numbers them from the
+    // start on a new connection. The player keeps steering the same way.
+    pub fn reset(&mut self) {
+        *self = Self {
+            current: self.current,
+            ..Self::default()
+        };
+    }
+
+    // Forgets the inputs up to `seq`, the server applied them.
+    pub fn ack(&mut self, seq: u32) {
+        while let Some((_, steer)) = self.pending.front().filter(|(pending, _)| *pending <= seq) {
+            self.acked = *steer;
+            self.pending.pop_front();
+        }
+    }
+}
+
+// One of the player's cells, moved locally with the inputs the server did
+// not apply yet on top of the last position it sent. Steps are the same as
+// the server's, see `common::Body`.
+#[derive(Component)]
+pub struct Prediction {
+    server_position: Vec2,
+    server_velocity: Vec2,
+    body: Body,
+    // Position before the last step, drawing happens in between.
+    previous: Vec2,
+    // What is drawn is off by this much after a correction, it shrinks
+    // every frame instead of jumping.
+    error: Vec2,
+}
+
+impl Prediction {
+    pub fn new(position: Vec2, radius: f32) -> Self {
+        Self {
+            server_position: position,
+            server_velocity: Vec2::ZERO,
+            body: Body {
+                x: position.x,
+                y: position.y,
+                radius,
+                ..Default::default()
+            },
+            previous: position,
+            error: Vec2::ZERO,
+        }
+    }
+
+    fn position(&self) -> Vec2 {
+        Vec2::new(self.body.x, self.body.y)
+    }
+
+    pub fn set_radius(&mut self, radius: f32) {
+        self.body.radius = radius;
+    }
+
+    // The authoritative state, used by the next `reconcile`.
+    pub fn update(&mut self, position: Vec2, velocity: Vec2) {
+        self.server_position = position;
+        self.server_velocity = velocity;
+    }
+
+    // Moves the cell by one server tick.
+    pub fn step(&mut self, steer: Steer) {
+        self.previous = self.position();
+        self.body.step(steer, TICK);
+    }
+
+    // Starts over from the server's state and replays the inputs it did not
+    // apply yet.
+    pub fn reconcile(&mut self, inputs: &Inputs) {
+        let (before, previous) = (self.position(), self.previous);
+        let (position, velocity) = (self.server_position, self.server_velocity);
+        self.body = Body::with_velocity(
+            position.x,
+            position.y,
+            velocity.x,
+            velocity.y,
+            self.body.radius,
+            inputs.acked,
+        );
+        for (_, steer) in &inputs.pending {
+            self.step(*steer);
+        }
+
+        // The step being drawn moves along, the error takes it back to
+        // where it was.
+        let correction = before - self.position();
+        self.previous = previous - correction;
+        self.error += correction;
+    }
+
+    // Where to draw the cell, `progress` of the way to the next step.
+    pub fn draw(&mut self, progress: f32, dt: f32) -> Vec2 {
+        self.error *= (-CORRECTION_RATE * dt).exp();
+        self.previous.lerp(self.position(), progress) + self.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_start_over_after_a_reset() {
+        let mut inputs = Inputs::default();
+        inputs.current = Steer::Direction(1.0, 0.0);
+        inputs.next();
+        inputs.next();
+        inputs.ack(1);
+        assert_eq!(inputs.acked, Steer::Direction(1.0, 0.0));
+
+        inputs.reset();
+        assert!(inputs.pending.is_empty());
+        assert_eq!(inputs.acked, Steer::default());
+        assert_eq!(inputs.next(), (1, Steer::Direction(1.0, 0.0)));
+
+        // A prediction of the new session only replays its own inputs.
+        let mut prediction = Prediction::new(Vec2::ZERO, 50.0);
+        prediction.reconcile(&inputs);
+        assert!(prediction.position().x > 0.0);
+        inputs.ack(1);
+        prediction.reconcile(&inputs);
+        assert_eq!(prediction.position(), Vec2::ZERO);
+    }
+}